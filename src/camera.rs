@@ -11,7 +11,8 @@ pub struct Camera {
     pub w: Vector3,
     pub lense_radius: f64,
     pub time_0: f64,
-    pub time_1: f64
+    pub time_1: f64,
+    pub pixel_spread: f64 // Angle subtended by a pixel, zero if rays carry no cone
 }
 
 impl Camera {
@@ -51,10 +52,18 @@ impl Camera {
             w,
             lense_radius,
             time_0,
-            time_1
+            time_1,
+            pixel_spread: 0.0
         }
     }
 
+    // Gives rays a cone one pixel wide for an image with the given number of rows, so textures can be filtered
+    pub fn with_image_height(mut self, image_height: usize) -> Camera {
+        let focus_dist = (self.lower_left_corner + 0.5 * self.horizontal + 0.5 * self.vertical - self.origin).length();
+        self.pixel_spread = self.vertical.length() / (focus_dist * image_height as f64);
+        self
+    }
+
     pub fn get_ray(&self, s: f64, t: f64) -> Ray {
         let rd = self.lense_radius * Vector3::random_in_unit_disk();
         let offset = self.u * rd.x + self.v * rd.y;
@@ -62,6 +71,6 @@ impl Camera {
             self.origin + offset,
             self.lower_left_corner + s * self.horizontal + t * self.vertical - self.origin - offset,
             random_double_range(self.time_0, self.time_1)
-            )
+            ).with_cone(0.0, self.pixel_spread)
     }
 }
@@ -0,0 +1,95 @@
+// Piecewise-constant distributions used for importance sampling tabulated functions like environment maps
+
+pub struct Distribution1D {
+    pub func: Vec<f64>,
+    pub cdf: Vec<f64>,
+    pub func_int: f64
+}
+
+impl Distribution1D {
+    pub fn new(func: &[f64]) -> Distribution1D {
+        let n = func.len();
+        let func: Vec<f64> = func.iter().map(|f| f.abs()).collect();
+        let mut cdf = vec![0.0; n + 1];
+
+        for i in 1..=n {
+            cdf[i] = cdf[i - 1] + func[i - 1] / n as f64;
+        }
+
+        let func_int = cdf[n];
+
+        if func_int == 0.0 {
+            // Fall back to uniform sampling when the function is zero everywhere
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as f64 / n as f64;
+            }
+        } else {
+            for c in cdf.iter_mut() {
+                *c /= func_int;
+            }
+        }
+
+        Distribution1D {
+            func,
+            cdf,
+            func_int
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    // Returns the sampled position in [0,1), its pdf and the index of the segment it fell in
+    pub fn sample_continuous(&self, u: f64) -> (f64, f64, usize) {
+        // Find the last cdf entry that is <= u
+        let offset = (self.cdf.partition_point(|c| *c <= u).max(1) - 1).min(self.count() - 1);
+
+        let mut du = u - self.cdf[offset];
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        if width > 0.0 {
+            du /= width;
+        }
+
+        let pdf = if self.func_int > 0.0 { self.func[offset] / self.func_int } else { 1.0 };
+
+        ((offset as f64 + du) / self.count() as f64, pdf, offset)
+    }
+
+    pub fn pdf(&self, x: f64) -> f64 {
+        let offset = ((x * self.count() as f64) as usize).min(self.count() - 1);
+        if self.func_int > 0.0 { self.func[offset] / self.func_int } else { 1.0 }
+    }
+}
+
+pub struct Distribution2D {
+    pub conditional: Vec<Distribution1D>,
+    pub marginal: Distribution1D
+}
+
+impl Distribution2D {
+    // func is laid out row by row, nu values per row and nv rows
+    pub fn new(func: &[f64], nu: usize, nv: usize) -> Distribution2D {
+        let conditional: Vec<Distribution1D> = (0..nv).map(|v| Distribution1D::new(&func[v * nu..(v + 1) * nu])).collect();
+        let marginal_func: Vec<f64> = conditional.iter().map(|c| c.func_int).collect();
+        let marginal = Distribution1D::new(&marginal_func);
+
+        Distribution2D {
+            conditional,
+            marginal
+        }
+    }
+
+    // Returns (x, y) in [0,1)^2 and the pdf with respect to that unit square
+    pub fn sample_continuous(&self, u0: f64, u1: f64) -> ((f64, f64), f64) {
+        let (y, pdf_y, v) = self.marginal.sample_continuous(u1);
+        let (x, pdf_x, _) = self.conditional[v].sample_continuous(u0);
+
+        ((x, y), pdf_x * pdf_y)
+    }
+
+    pub fn pdf(&self, x: f64, y: f64) -> f64 {
+        let v = ((y * self.marginal.count() as f64) as usize).min(self.marginal.count() - 1);
+        self.conditional[v].pdf(x) * self.marginal.pdf(y)
+    }
+}
@@ -0,0 +1,196 @@
+use crate::math::*;
+use crate::distribution::*;
+use crate::sky::*;
+
+pub enum Background {
+    Color(Color),
+    Environment(Box<EnvironmentMap>),
+    Sky(Box<Sky>)
+}
+
+impl Background {
+    // Radiance arriving from the given direction for rays that escape the scene
+    pub fn value(&self, direction: &Vector3) -> Color {
+        match self {
+            Background::Color(color) => *color,
+            Background::Environment(map) => map.value(direction),
+            Background::Sky(sky) => sky.value(direction)
+        }
+    }
+
+    // Samples a direction towards the background, returning (direction, radiance, solid angle pdf)
+    pub fn sample(&self) -> Option<(Vector3, Color, f64)> {
+        match self {
+            Background::Color(_) => None,
+            Background::Environment(map) => map.sample(),
+            Background::Sky(sky) => sky.sample()
+        }
+    }
+
+    pub fn pdf(&self, direction: &Vector3) -> f64 {
+        match self {
+            Background::Color(_) => 0.0,
+            Background::Environment(map) => map.pdf(direction),
+            Background::Sky(sky) => sky.pdf(direction)
+        }
+    }
+}
+
+// Equirectangular environment map, using the same (u, v) convention as sphere_uv
+pub struct EnvironmentMap {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<f32>, // Linear RGB, row by row starting at +Y
+    pub sin_rotation: f64,
+    pub cos_rotation: f64,
+    pub intensity: f64,
+    pub distribution: Distribution2D
+}
+
+impl EnvironmentMap {
+    // rotation is in degrees around the Y axis
+    pub fn load(path: &str, rotation: f64, intensity: f64) -> EnvironmentMap {
+        let (width, height, data) = if path.ends_with(".exr") {
+            Self::load_exr(path)
+        } else {
+            Self::load_stb(path)
+        };
+
+        Self::new(width, height, data, rotation, intensity)
+    }
+
+    pub fn new(width: usize, height: usize, data: Vec<f32>, rotation: f64, intensity: f64) -> EnvironmentMap {
+        // Build a luminance based sampling distribution. Rows are weighted by sin(theta) to
+        // account for the stretching of the equirectangular mapping towards the poles.
+        let mut func = vec![0.0; width * height];
+        for j in 0..height {
+            let sin_theta = (PI * (j as f64 + 0.5) / height as f64).sin();
+            for i in 0..width {
+                let index = 3 * (j * width + i);
+                let color = Color::new(data[index] as f64, data[index + 1] as f64, data[index + 2] as f64);
+                func[j * width + i] = luminance(&color) * sin_theta;
+            }
+        }
+
+        let radians = degrees_to_radians(rotation);
+
+        EnvironmentMap {
+            width,
+            height,
+            data,
+            sin_rotation: radians.sin(),
+            cos_rotation: radians.cos(),
+            intensity,
+            distribution: Distribution2D::new(&func, width, height)
+        }
+    }
+
+    fn load_stb(path: &str) -> (usize, usize, Vec<f32>) {
+        match stb_image::image::load(path) {
+            stb_image::image::LoadResult::Error(err) => {
+                panic!("{}", err);
+            },
+            stb_image::image::LoadResult::ImageF32(image) => {
+                let mut data = Vec::with_capacity(image.width * image.height * 3);
+                for pixel in image.data.chunks(image.depth) {
+                    for c in 0..3 {
+                        data.push(pixel[c.min(image.depth - 1)]);
+                    }
+                }
+                (image.width, image.height, data)
+            },
+            stb_image::image::LoadResult::ImageU8(image) => {
+                // Low dynamic range images are assumed to be gamma encoded
+                let mut data = Vec::with_capacity(image.width * image.height * 3);
+                for pixel in image.data.chunks(image.depth) {
+                    for c in 0..3 {
+                        data.push((pixel[c.min(image.depth - 1)] as f32 / 255.0).powf(2.2));
+                    }
+                }
+                (image.width, image.height, data)
+            }
+        }
+    }
+
+    fn load_exr(path: &str) -> (usize, usize, Vec<f32>) {
+        let image = exr::prelude::read_first_rgba_layer_from_file(
+            path,
+            |resolution, _| (resolution.width(), vec![0.0f32; resolution.width() * resolution.height() * 3]),
+            |(width, data), position, (r, g, b, _a): (f32, f32, f32, f32)| {
+                let index = 3 * (position.y() * *width + position.x());
+                data[index] = r;
+                data[index + 1] = g;
+                data[index + 2] = b;
+            }
+        );
+
+        match image {
+            Ok(image) => {
+                let size = image.layer_data.size;
+                (size.width(), size.height(), image.layer_data.channel_data.pixels.1)
+            },
+            Err(err) => {
+                panic!("{}", err);
+            }
+        }
+    }
+
+    // World space direction to the map's local frame
+    fn to_local(&self, d: &Vector3) -> Vector3 {
+        Vector3::new(
+            self.cos_rotation * d.x - self.sin_rotation * d.z,
+            d.y,
+            self.sin_rotation * d.x + self.cos_rotation * d.z
+        )
+    }
+
+    fn to_world(&self, d: &Vector3) -> Vector3 {
+        Vector3::new(
+            self.cos_rotation * d.x + self.sin_rotation * d.z,
+            d.y,
+            -self.sin_rotation * d.x + self.cos_rotation * d.z
+        )
+    }
+
+    pub fn value(&self, direction: &Vector3) -> Color {
+        let (u, v) = sphere_uv(&Vector3::normalize(&self.to_local(direction)));
+
+        let i = ((u * self.width as f64) as usize).min(self.width - 1);
+        let j = (((1.0 - v) * self.height as f64) as usize).min(self.height - 1);
+        let index = 3 * (j * self.width + i);
+
+        self.intensity * Color::new(self.data[index] as f64, self.data[index + 1] as f64, self.data[index + 2] as f64)
+    }
+
+    pub fn sample(&self) -> Option<(Vector3, Color, f64)> {
+        let ((s, t), map_pdf) = self.distribution.sample_continuous(random_double(), random_double());
+        if map_pdf == 0.0 {
+            return None;
+        }
+
+        // t runs from +Y (t = 0) to -Y (t = 1), s follows the u coordinate of sphere_uv
+        let theta = t * PI;
+        let phi = s * 2.0 * PI;
+        let sin_theta = theta.sin();
+        if sin_theta == 0.0 {
+            return None;
+        }
+
+        let local = Vector3::new(-phi.cos() * sin_theta, theta.cos(), phi.sin() * sin_theta);
+        let direction = self.to_world(&local);
+        let pdf = map_pdf / (2.0 * PI * PI * sin_theta);
+
+        Some((direction, self.value(&direction), pdf))
+    }
+
+    pub fn pdf(&self, direction: &Vector3) -> f64 {
+        let (u, v) = sphere_uv(&Vector3::normalize(&self.to_local(direction)));
+        let theta = (1.0 - v) * PI;
+        let sin_theta = theta.sin();
+        if sin_theta == 0.0 {
+            return 0.0;
+        }
+
+        self.distribution.pdf(u, 1.0 - v) / (2.0 * PI * PI * sin_theta)
+    }
+}
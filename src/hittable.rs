@@ -3,7 +3,7 @@ use crate::ray::*;
 use crate::material::*;
 use crate::aabb::*;
 
-#[derive(Default)]
+#[derive(Default, Copy, Clone)]
 pub struct HitRecord {
     pub point: Point3,
     pub normal: Vector3,
@@ -11,7 +11,29 @@ pub struct HitRecord {
     pub front_face: bool,
     pub mat_handle: MaterialHandle,
     pub u: f64,
-    pub v: f64
+    pub v: f64,
+    // Partial derivatives of the hit point with respect to u and v, spanning the tangent plane
+    pub dpdu: Vector3,
+    pub dpdv: Vector3,
+    // Change of the texture coordinates across the footprint of the ray's cone, along its two axes on the surface
+    pub dudx: f64,
+    pub dvdx: f64,
+    pub dudy: f64,
+    pub dvdy: f64,
+    // Bases of the coats shading the hit and their shading normals, resolved once along with the coats so that
+    // sampling and evaluation agree on which component of a mix they are. The coat at nesting level coat_level,
+    // counted from the outermost, finds its base at that index
+    pub coat_bases: [(MaterialHandle, Vector3); MAX_COAT_LAYERS],
+    pub coat_level: usize,
+    // Set by Hittable::Hidden so the integrator can look through the object for some kinds of rays
+    pub hidden_from_camera: bool,
+    pub hidden_from_reflections: bool,
+    // Set by resolve_material: the material shading the hit once wrappers and mixes are resolved, and the probability
+    // that the surface is there rather than cut out by alpha masks
+    pub resolved_handle: MaterialHandle,
+    pub opacity: f64,
+    // Set by hit_hittables_opaque where the surface on a medium's boundary is cut out
+    pub cut_out: bool
 }
 
 impl HitRecord {
@@ -24,6 +46,44 @@ impl HitRecord {
         self.front_face = Vector3::dot(&ray.direction, &outward_normal) < 0.0;
         self.normal = if self.front_face { *outward_normal } else { -outward_normal };
     }
+
+    // Projects the ray's cone onto the tangent plane, an ellipse stretched by the angle of incidence, and expresses
+    // its axes in texture coordinates through dpdu and dpdv
+    pub fn set_texture_footprint(&mut self, ray: &Ray) {
+        let width = ray.cone_width_at(self.t);
+        if width <= 0.0 {
+            return;
+        }
+
+        let direction = Vector3::normalize(&ray.direction);
+        let cos_theta = Vector3::dot(&direction, &self.normal).abs().max(0.05);
+
+        let mut minor = Vector3::cross(&direction, &self.normal);
+        if minor.near_zero() {
+            minor = if self.dpdu.near_zero() { Vector3::cross(&self.normal, &Vector3::new(1.0, 0.0, 0.0)) } else { self.dpdu };
+        }
+        let minor = Vector3::normalize(&minor);
+        let major = Vector3::cross(&self.normal, &minor);
+
+        // Least squares solution of dp = dpdu du + dpdv dv
+        let (a, b, c) = (Vector3::dot(&self.dpdu, &self.dpdu), Vector3::dot(&self.dpdu, &self.dpdv), Vector3::dot(&self.dpdv, &self.dpdv));
+        let det = a * c - b * b;
+        if det.abs() < 1e-12 {
+            return;
+        }
+
+        let solve = |dp: Vector3| -> (f64, f64) {
+            let (pu, pv) = (Vector3::dot(&self.dpdu, &dp), Vector3::dot(&self.dpdv, &dp));
+            ((c * pu - b * pv) / det, (a * pv - b * pu) / det)
+        };
+
+        let (dudx, dvdx) = solve(width * minor);
+        let (dudy, dvdy) = solve(width / cos_theta * major);
+        self.dudx = dudx;
+        self.dvdx = dvdx;
+        self.dudy = dudy;
+        self.dvdy = dvdy;
+    }
 }
 
 #[derive(Clone)]
@@ -37,7 +97,9 @@ pub enum Hittable {
     Box             { mat_handle: MaterialHandle, min: Point3, max: Point3, sides: Vec<Hittable> },
     Translate       { offset: Vector3, ptr: Box<Hittable> },
     RotateY         { sin_theta: f64, cos_theta: f64, has_box: bool, bbox: AABB, ptr: Box<Hittable> },
-    ConstantMedium  { phase_function: MaterialHandle, boundary: Box<Hittable>, neg_inv_density: f64 }
+    ConstantMedium  { phase_function: MaterialHandle, boundary: Box<Hittable>, neg_inv_density: f64 },
+    FlipFace        { ptr: Box<Hittable> },
+    Hidden          { from_camera: bool, from_reflections: bool, ptr: Box<Hittable> }
 }
 
 pub fn hit_hittables(hittables: &Vec<Hittable>, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
@@ -54,6 +116,28 @@ pub fn hit_hittables(hittables: &Vec<Hittable>, ray: &Ray, t_min: f64, t_max: f6
     rec
 }
 
+// Closest hit that isn't cut out by an alpha mask on its material. Cut out boundaries of a medium are still returned,
+// marked so that rays cross them without interacting
+pub fn hit_hittables_opaque(hittables: &Vec<Hittable>, materials: &[Material], ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    let mut t_min = t_min;
+    loop {
+        let mut rec = hit_hittables(hittables, ray, t_min, t_max)?;
+
+        rec.set_texture_footprint(ray);
+        resolve_material(materials, rec.mat_handle, ray, &mut rec);
+        if rec.opacity >= 1.0 || random_double() < rec.opacity {
+            return Some(rec);
+        }
+
+        if materials[rec.mat_handle.0 - 1].interior_medium(&rec).is_some() {
+            rec.cut_out = true;
+            return Some(rec);
+        }
+
+        t_min = rec.t + 0.001;
+    }
+}
+
 pub fn hittables_bounding_box(hittables: &Vec<Hittable>, time_0: f64, time_1: f64) -> Option<AABB> {
     if hittables.len() == 0 {
         return None;
@@ -132,14 +216,16 @@ impl Hittable {
     pub fn new_box(min: Point3, max: Point3, mat_handle: MaterialHandle) -> Hittable {
         let mut sides = Vec::new();
 
+        // Rectangles face the positive axis, so the sides at min are flipped for every face of the box to point
+        // outwards, which media and glass rely on to tell entering from leaving
         sides.push(Hittable::XYRect { mat_handle, x0: min.x, x1: max.x, y0: min.y, y1: max.y, k: max.z });
-        sides.push(Hittable::XYRect { mat_handle, x0: min.x, x1: max.x, y0: min.y, y1: max.y, k: min.z });
+        sides.push(Hittable::new_flip_face(Hittable::XYRect { mat_handle, x0: min.x, x1: max.x, y0: min.y, y1: max.y, k: min.z }));
 
         sides.push(Hittable::XZRect { mat_handle, x0: min.x, x1: max.x, z0: min.z, z1: max.z, k: max.y });
-        sides.push(Hittable::XZRect { mat_handle, x0: min.x, x1: max.x, z0: min.z, z1: max.z, k: min.y });
+        sides.push(Hittable::new_flip_face(Hittable::XZRect { mat_handle, x0: min.x, x1: max.x, z0: min.z, z1: max.z, k: min.y }));
 
         sides.push(Hittable::YZRect { mat_handle, y0: min.y, y1: max.y, z0: min.z, z1: max.z, k: max.x });
-        sides.push(Hittable::YZRect { mat_handle, y0: min.y, y1: max.y, z0: min.z, z1: max.z, k: min.x });
+        sides.push(Hittable::new_flip_face(Hittable::YZRect { mat_handle, y0: min.y, y1: max.y, z0: min.z, z1: max.z, k: min.x }));
 
         Hittable::Box { mat_handle, min, max, sides }
     }
@@ -198,6 +284,8 @@ impl Hittable {
         }
     }
 
+    // Only valid for convex boundaries, which rays may also start inside of since the entry is clamped to t_min, as for
+    // a camera in fog. Material::MediumInterface handles any closed boundary
     pub fn new_constant_medium(hittable: Hittable, d: f64, mat_handle: MaterialHandle) -> Hittable {
         Hittable::ConstantMedium {
             phase_function: mat_handle,
@@ -206,6 +294,17 @@ impl Hittable {
         }
     }
 
+    // Swaps the front and back faces, e.g. to make a one-sided ceiling light emit downwards
+    pub fn new_flip_face(hittable: Hittable) -> Hittable {
+        Hittable::FlipFace { ptr: Box::new(hittable) }
+    }
+
+    // Hides the object from camera rays and/or from rays leaving specular surfaces, while it still lights the
+    // scene through diffuse bounces
+    pub fn new_hidden(hittable: Hittable, from_camera: bool, from_reflections: bool) -> Hittable {
+        Hittable::Hidden { from_camera, from_reflections, ptr: Box::new(hittable) }
+    }
+
     pub fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
         match self {
             Hittable::Sphere { mat_handle, center, radius } => {
@@ -247,6 +346,23 @@ impl Hittable {
             },
             Hittable::ConstantMedium { phase_function, boundary, neg_inv_density } => {
                 Self::hit_constant_medium(boundary, *phase_function, *neg_inv_density, ray, t_min, t_max)
+            },
+            Hittable::FlipFace { ptr } => {
+                if let Some(mut rec) = ptr.hit(ray, t_min, t_max) {
+                    rec.front_face = !rec.front_face;
+                    Some(rec)
+                } else {
+                    None
+                }
+            },
+            Hittable::Hidden { from_camera, from_reflections, ptr } => {
+                if let Some(mut rec) = ptr.hit(ray, t_min, t_max) {
+                    rec.hidden_from_camera |= *from_camera;
+                    rec.hidden_from_reflections |= *from_reflections;
+                    Some(rec)
+                } else {
+                    None
+                }
             }
         }
     }
@@ -284,6 +400,12 @@ impl Hittable {
         rec.u = u;
         rec.v = v;
 
+        // Derivatives of the sphere_uv parameterization, with the v derivative kept finite at the poles
+        let n = outward_normal;
+        let sin_theta = (1.0 - n.y * n.y).sqrt().max(1e-8);
+        rec.dpdu = 2.0 * PI * radius * Vector3::new(n.z, 0.0, -n.x);
+        rec.dpdv = PI * radius * Vector3::new(-n.x * n.y / sin_theta, sin_theta, -n.y * n.z / sin_theta);
+
         Some(rec)
     }
 
@@ -322,6 +444,8 @@ impl Hittable {
         let mut rec = HitRecord::new();
         rec.u = (x - x0) / (x1 - x0);
         rec.v = (y - y0) / (y1 - y0);
+        rec.dpdu = Vector3::new(x1 - x0, 0.0, 0.0);
+        rec.dpdv = Vector3::new(0.0, y1 - y0, 0.0);
         rec.t = t;
         let outward_normal = Vector3::new(0.0, 0.0, 1.0);
         rec.set_face_normal(ray, &outward_normal);
@@ -348,6 +472,8 @@ impl Hittable {
         let mut rec = HitRecord::new();
         rec.u = (x - x0) / (x1 - x0);
         rec.v = (z - z0) / (z1 - z0);
+        rec.dpdu = Vector3::new(x1 - x0, 0.0, 0.0);
+        rec.dpdv = Vector3::new(0.0, 0.0, z1 - z0);
         rec.t = t;
         let outward_normal = Vector3::new(0.0, 1.0, 0.0);
         rec.set_face_normal(ray, &outward_normal);
@@ -374,6 +500,8 @@ impl Hittable {
         let mut rec = HitRecord::new();
         rec.u = (y - y0) / (y1 - y0);
         rec.v = (z - z0) / (z1 - z0);
+        rec.dpdu = Vector3::new(0.0, y1 - y0, 0.0);
+        rec.dpdv = Vector3::new(0.0, 0.0, z1 - z0);
         rec.t = t;
         let outward_normal = Vector3::new(1.0, 0.0, 0.0);
         rec.set_face_normal(ray, &outward_normal);
@@ -408,6 +536,12 @@ impl Hittable {
             rec.point = p;
             rec.set_face_normal(&rotated_ray, &normal);
 
+            let (dpdu, dpdv) = (rec.dpdu, rec.dpdv);
+            rec.dpdu.x = cos_theta * dpdu.x + sin_theta * dpdu.z;
+            rec.dpdu.z = -sin_theta * dpdu.x + cos_theta * dpdu.z;
+            rec.dpdv.x = cos_theta * dpdv.x + sin_theta * dpdv.z;
+            rec.dpdv.z = -sin_theta * dpdv.x + cos_theta * dpdv.z;
+
             Some(rec)
         } else {
             None
@@ -523,6 +657,12 @@ impl Hittable {
             },
             Hittable::ConstantMedium { phase_function: _, boundary, neg_inv_density: _ } => {
                 boundary.bounding_box(time_0, time_1)
+            },
+            Hittable::FlipFace { ptr } => {
+                ptr.bounding_box(time_0, time_1)
+            },
+            Hittable::Hidden { from_camera: _, from_reflections: _, ptr } => {
+                ptr.bounding_box(time_0, time_1)
             }
         }
     }
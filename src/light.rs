@@ -0,0 +1,65 @@
+use crate::math::*;
+
+// Lights without geometry. They can't be hit by rays and only contribute through explicit light sampling.
+pub enum Light {
+    Point { position: Point3, intensity: Color },
+    Spot { position: Point3, direction: Vector3, intensity: Color, cos_total_width: f64, cos_falloff_start: f64 },
+    Directional { direction: Vector3, irradiance: Color }
+}
+
+impl Light {
+    // Angles are the full cone half-angle and the half-angle where the falloff starts, in degrees
+    pub fn new_spot(position: Point3, look_at: Point3, intensity: Color, total_width: f64, falloff_start: f64) -> Light {
+        Light::Spot {
+            position,
+            direction: Vector3::normalize(&(look_at - position)),
+            intensity,
+            cos_total_width: degrees_to_radians(total_width).cos(),
+            cos_falloff_start: degrees_to_radians(falloff_start).cos()
+        }
+    }
+
+    // direction is the direction the light travels in
+    pub fn new_directional(direction: Vector3, irradiance: Color) -> Light {
+        Light::Directional {
+            direction: Vector3::normalize(&direction),
+            irradiance
+        }
+    }
+
+    // Returns the unit direction towards the light, the incident radiance and the distance to the light
+    pub fn sample_li(&self, p: &Point3) -> Option<(Vector3, Color, f64)> {
+        match self {
+            Light::Point { position, intensity } => {
+                let to_light = *position - *p;
+                let distance = to_light.length();
+                Some((to_light / distance, *intensity / (distance * distance), distance))
+            },
+            Light::Spot { position, direction, intensity, cos_total_width, cos_falloff_start } => {
+                let to_light = *position - *p;
+                let distance = to_light.length();
+                let wi = to_light / distance;
+                let falloff = Self::spot_falloff(Vector3::dot(&(-wi), direction), *cos_total_width, *cos_falloff_start);
+                if falloff == 0.0 {
+                    return None;
+                }
+                Some((wi, falloff * *intensity / (distance * distance), distance))
+            },
+            Light::Directional { direction, irradiance } => {
+                Some((-direction, *irradiance, INFINITY))
+            }
+        }
+    }
+
+    fn spot_falloff(cos_theta: f64, cos_total_width: f64, cos_falloff_start: f64) -> f64 {
+        if cos_theta < cos_total_width {
+            return 0.0;
+        }
+        if cos_theta >= cos_falloff_start {
+            return 1.0;
+        }
+
+        let delta = (cos_theta - cos_total_width) / (cos_falloff_start - cos_total_width);
+        (delta * delta) * (delta * delta)
+    }
+}
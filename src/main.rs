@@ -6,8 +6,17 @@ mod material;
 mod aabb;
 mod texture;
 mod perlin;
-
-//use aabb::*;
+mod onb;
+mod distribution;
+mod environment;
+mod sky;
+mod light;
+mod microfacet;
+mod principled;
+mod spectrum;
+mod medium;
+
+use aabb::*;
 use math::*;
 use ray::*;
 use camera::*;
@@ -15,31 +24,336 @@ use hittable::*;
 use material::*;
 use texture::*;
 use perlin::*;
+use environment::*;
+use sky::*;
+use light::*;
+use principled::*;
+use spectrum::*;
+use medium::*;
+
+// Power heuristic for multiple importance sampling with one sample taken from each strategy
+fn power_heuristic(f_pdf: f64, g_pdf: f64) -> f64 {
+    let f = f_pdf * f_pdf;
+    let g = g_pdf * g_pdf;
+    if f + g == 0.0 { 0.0 } else { f / (f + g) }
+}
 
-fn ray_color(ray: &Ray, background_color: &Color, hittables: &Vec<Hittable>, depth: i32, materials: &Vec<Material>) -> Color {
-    // If we've exceeded the ray bounce limit, no more light is gathered
-    if depth <= 0 {
-        return Color::new(0.0, 0.0, 0.0);
+// In spectral mode, RGB reflectances and emissions are upsampled at the path's wavelengths
+fn path_reflectance(color: &Color, wavelengths: &Option<Vector3>) -> Color {
+    match wavelengths {
+        Some(wavelengths) => rgb_to_reflectance_samples(color, wavelengths),
+        None => *color
+    }
+}
+
+fn path_radiance(color: &Color, wavelengths: &Option<Vector3>) -> Color {
+    match wavelengths {
+        Some(wavelengths) => rgb_to_illuminant_samples(color, wavelengths),
+        None => *color
     }
+}
 
-    if let Some(rec) = hit_hittables(hittables, ray, 0.001, INFINITY) {
-        let material = &materials[rec.mat_handle.0 - 1];
-        
-        let emitted = material.emitted(rec.u, rec.v, &rec.point);
+// Next event estimation towards the background, weighted against BSDF sampling
+fn sample_background(ray: &Ray, rec: &HitRecord, material: &Material, background: &Background, world: &World, media: &MediumStack, wavelengths: &Option<Vector3>) -> Color {
+    if let Some((direction, radiance, light_pdf)) = background.sample() {
+        let f = material.eval(ray, rec, &direction, &world.materials);
+        if f.near_zero() {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+
+        let shadow_ray = Ray::with_time(rec.point, direction, ray.time);
+        let transmittance = shadow_transmittance(world, &shadow_ray, INFINITY, media, wavelengths);
+        if transmittance.near_zero() {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+
+        let weight = power_heuristic(light_pdf, material.pdf(ray, rec, &direction, &world.materials));
+        return transmittance * path_reflectance(&f, wavelengths) * path_radiance(&radiance, wavelengths) * (weight / light_pdf);
+    }
+
+    Color::new(0.0, 0.0, 0.0)
+}
+
+// Direct lighting from delta lights, which can only be reached by sampling them explicitly
+fn sample_lights(ray: &Ray, rec: &HitRecord, material: &Material, world: &World, media: &MediumStack, wavelengths: &Option<Vector3>) -> Color {
+    let mut color = Color::new(0.0, 0.0, 0.0);
+
+    for light in &world.lights {
+        if let Some((direction, radiance, distance)) = light.sample_li(&rec.point) {
+            let f = material.eval(ray, rec, &direction, &world.materials);
+            if f.near_zero() {
+                continue;
+            }
+
+            let shadow_ray = Ray::with_time(rec.point, direction, ray.time);
+            let transmittance = shadow_transmittance(world, &shadow_ray, distance - 0.001, media, wavelengths);
+            color += transmittance * path_reflectance(&f, wavelengths) * path_radiance(&radiance, wavelengths);
+        }
+    }
+
+    color
+}
+
+// Fraction of light reaching the shadow ray's origin from t_max, attenuated by the media it passes through. Any
+// surface other than a medium boundary blocks it
+fn shadow_transmittance(world: &World, ray: &Ray, t_max: f64, media: &MediumStack, wavelengths: &Option<Vector3>) -> Color {
+    let mut transmittance = Color::new(1.0, 1.0, 1.0);
+    let mut ray = *ray;
+    let mut t_max = t_max;
+    let mut media = media.clone();
+
+    loop {
+        let hit = hit_hittables_opaque(&world.hittables, &world.materials, &ray, 0.001, t_max);
+        if let Some(medium) = media.current() {
+            transmittance = transmittance * medium.transmittance(&ray, hit.as_ref().map_or(t_max, |rec| rec.t));
+        }
+
+        let rec = match hit {
+            Some(rec) => rec,
+            None => {
+                return transmittance;
+            }
+        };
+
+        let boundary = &world.materials[rec.mat_handle.0 - 1];
+        if !boundary.is_invisible() && !rec.cut_out {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+
+        cross_boundary(&mut media, boundary, boundary, &rec, wavelengths);
+        ray = Ray::with_time(rec.point, ray.direction, ray.time);
+        t_max -= rec.t;
+    }
+}
 
-        if let Some((scattered, attenuation)) = material.scatter(ray, &rec) {
-            return emitted + attenuation * ray_color(&scattered, background_color, hittables, depth - 1, materials);
+// A ray passing through a surface enters the medium inside through a front face, and leaves it through a back face.
+// The medium is attached to the boundary material, or to the resolved material for subsurface scattering and
+// tinted glass. A medium bounded by tinted glass also takes on the glass's absorption
+fn cross_boundary(media: &mut MediumStack, boundary: &Material, material: &Material, rec: &HitRecord, wavelengths: &Option<Vector3>) {
+    if rec.front_face {
+        let mut medium = match (boundary.interior_medium(rec), material.absorption()) {
+            (Some(interior), Some(absorption)) if !std::ptr::eq(boundary, material) => Some(interior.with_absorption(absorption)),
+            (Some(interior), _) => Some(interior),
+            (None, _) => material.interior_medium(rec)
+        };
+        if let (Some(current), Some(wavelengths)) = (&medium, wavelengths) {
+            medium = Some(current.to_wavelengths(wavelengths));
+        }
+        media.enter(rec.mat_handle, medium);
+    } else {
+        media.exit(rec.mat_handle);
+    }
+}
+
+// Media enclosing a point, found by following a ray from it to infinity: boundaries it leaves without having entered
+// them surround the point, so a camera can start inside fog or under water
+fn enclosing_media(world: &World, point: &Point3) -> MediumStack {
+    let ray = Ray::with_time(*point, Vector3::new(0.267, 0.943, 0.198), 0.0);
+
+    let mut entered = Vec::new();
+    let mut enclosing = Vec::new();
+    let mut t_min = 0.001;
+    while let Some(rec) = hit_hittables(&world.hittables, &ray, t_min, INFINITY) {
+        t_min = rec.t + 0.001;
+
+        let boundary = &world.materials[rec.mat_handle.0 - 1];
+        let medium = match boundary.interior_medium(&rec) {
+            Some(medium) => medium,
+            None => { continue; }
+        };
+
+        if rec.front_face {
+            entered.push(rec.mat_handle.0);
+        } else if let Some(index) = entered.iter().rposition(|handle| *handle == rec.mat_handle.0) {
+            entered.remove(index);
         } else {
-            return emitted;
+            enclosing.push((rec.mat_handle, medium));
+        }
+    }
+
+    // The first boundary left is the innermost
+    let mut media = MediumStack::new();
+    for (boundary, medium) in enclosing.into_iter().rev() {
+        media.enter(boundary, Some(medium));
+    }
+
+    media
+}
+
+// Finds the closest hit, looking through objects hidden from this kind of ray
+fn hit_visible(world: &World, ray: &Ray, camera_ray: bool, specular_bounce: bool) -> Option<HitRecord> {
+    let mut t_min = 0.001;
+    loop {
+        let rec = hit_hittables_opaque(&world.hittables, &world.materials, ray, t_min, INFINITY)?;
+
+        let hidden = if camera_ray { rec.hidden_from_camera } else { specular_bounce && rec.hidden_from_reflections };
+        if !hidden {
+            return Some(rec);
+        }
+
+        t_min = rec.t + 0.001;
+    }
+}
+
+// Russian roulette: terminate dark paths with probability 1 - p and boost the survivors by 1 / p, which keeps
+// the estimator unbiased
+fn survives_russian_roulette(throughput: &mut Color) -> bool {
+    let p = throughput.x.max(throughput.y).max(throughput.z).min(0.95);
+    if random_double() >= p {
+        return false;
+    }
+
+    *throughput = *throughput / p;
+    true
+}
+
+// wavelengths is set for spectral rendering, in which case the returned color holds the radiance at those
+// three wavelengths instead of RGB
+// camera_media are the media enclosing the camera
+fn ray_color(ray: &Ray, background: &Background, world: &World, camera_media: &MediumStack, max_depth: i32, rr_min_depth: i32, wavelengths: Option<Vector3>) -> Color {
+    let materials = &world.materials;
+
+    let mut ray = *ray;
+    let mut color = Color::new(0.0, 0.0, 0.0);
+    let mut throughput = Color::new(1.0, 1.0, 1.0);
+
+    // The pdf of the last scattered ray, used to weight background hits against light sampling
+    let mut scatter_pdf = 0.0;
+    let mut specular_bounce = true;
+
+    // Media the path is currently inside of, the innermost one scattering light
+    let mut media = match &wavelengths {
+        Some(wavelengths) => camera_media.to_wavelengths(wavelengths),
+        None => camera_media.clone()
+    };
+
+    // Distances in media are sampled in one color channel per path. The path's pdf in every channel is tracked
+    // relative to their average, which throughput is divided by, to weight the channels against each other
+    let channel = random_int_range(0, 2) as usize;
+    let mut channel_pdf = Color::new(1.0, 1.0, 1.0);
+
+    for depth in 0..max_depth {
+        // Find the next surface or medium interaction, crossing surfaces that only delimit media on the way
+        let (hit, scatter) = loop {
+            let hit = hit_visible(world, &ray, depth == 0, specular_bounce);
+
+            let mut scatter = None;
+            if let Some(current) = media.current() {
+                let (t, f, pdf) = current.sample(&ray, hit.as_ref().map_or(INFINITY, |rec| rec.t), channel);
+
+                let path_pdf = channel_pdf * pdf;
+                let average_pdf = (path_pdf.x + path_pdf.y + path_pdf.z) / 3.0;
+                if average_pdf <= 0.0 {
+                    return color;
+                }
+
+                // Emission is gathered at collisions, weighted by how densely they occur in each channel. That is the
+                // pdf of a homogeneous collision, and cancels in heterogeneous media, whose pdf is the same everywhere
+                if let Some(t) = t {
+                    color += throughput * pdf * current.emitted(&ray.at(t), &wavelengths) / average_pdf;
+                }
+                throughput = throughput * f / average_pdf;
+                channel_pdf = path_pdf / average_pdf;
+                scatter = t.map(|t| (t, current.phase_function()));
+            }
+
+            match &hit {
+                Some(rec) if scatter.is_none() && (rec.cut_out || materials[rec.mat_handle.0 - 1].is_invisible()) => {
+                    let boundary = &materials[rec.mat_handle.0 - 1];
+                    cross_boundary(&mut media, boundary, boundary, rec, &wavelengths);
+                    ray = Ray::with_wavelength(rec.point, ray.direction, ray.time, ray.wavelength).with_cone(ray.cone_width_at(rec.t), ray.cone_spread);
+                },
+                _ => {
+                    break (hit, scatter);
+                }
+            }
+        };
+
+        if let Some((t, phase_function)) = scatter {
+            let mut rec = HitRecord::new();
+            rec.point = ray.at(t);
+            rec.front_face = true;
+
+            color += throughput * sample_background(&ray, &rec, &phase_function, background, world, &media, &wavelengths);
+            color += throughput * sample_lights(&ray, &rec, &phase_function, world, &media, &wavelengths);
+
+            // Phase functions are sampled exactly, leaving the throughput as it is
+            let srec = match phase_function.sample(&ray, &rec, materials) {
+                Some(srec) => srec,
+                None => break
+            };
+            ray = srec.ray;
+            scatter_pdf = srec.pdf;
+            specular_bounce = false;
+
+            if depth >= rr_min_depth && !survives_russian_roulette(&mut throughput) {
+                break;
+            }
+            continue;
+        }
+
+        let rec = match hit {
+            Some(rec) => rec,
+            None => {
+                let weight = if specular_bounce { 1.0 } else { power_heuristic(scatter_pdf, background.pdf(&ray.direction)) };
+                color += throughput * path_radiance(&background.value(&ray.direction), &wavelengths) * weight;
+                break;
+            }
+        };
+
+        // Hits come with their material resolved, see hit_hittables_opaque
+        let boundary = &materials[rec.mat_handle.0 - 1];
+        let material = &materials[rec.resolved_handle.0 - 1];
+
+        color += throughput * match &wavelengths {
+            Some(wavelengths) => material.emitted_samples(&rec, wavelengths),
+            None => material.emitted(&rec)
+        };
+
+        if let Some(wavelengths) = &wavelengths {
+            if ray.wavelength.is_none() && material.is_dispersive() {
+                // The secondary wavelengths can't follow the hero wavelength through a dispersive interface
+                ray.wavelength = Some(wavelengths.x);
+                throughput = Color::new(3.0 * throughput.x, 0.0, 0.0);
+            }
+        }
+
+        let srec = match material.sample(&ray, &rec, materials) {
+            Some(srec) => srec,
+            None => break
+        };
+
+        if material.has_non_delta_lobe(&rec, materials) {
+            color += throughput * sample_background(&ray, &rec, material, background, world, &media, &wavelengths);
+            color += throughput * sample_lights(&ray, &rec, material, world, &media, &wavelengths);
+        }
+
+        throughput = throughput * path_reflectance(&srec.attenuation, &wavelengths) / srec.pdf;
+        scatter_pdf = srec.pdf;
+        specular_bounce = srec.is_specular;
+
+        // Transmission through the surface enters or leaves the object's interior
+        if Vector3::dot(&srec.ray.direction, &rec.normal) < 0.0 && !material.is_phase_function() {
+            cross_boundary(&mut media, boundary, material, &rec, &wavelengths);
+        }
+
+        if depth >= rr_min_depth && !survives_russian_roulette(&mut throughput) {
+            break;
         }
-    } 
 
-    *background_color
+        // Specular bounces carry the cone on, ignoring the curvature of the surface. Glossy and diffuse ones spread
+        // the path out far more than a pixel, and their noise hides any texture aliasing
+        ray = if srec.is_specular { srec.ray.with_cone(ray.cone_width_at(rec.t), ray.cone_spread) } else { srec.ray };
+    }
+
+    // If we've exceeded the ray bounce limit, no more light is gathered
+    color
 }
 
 struct World {
     pub materials: Vec<Material>,
-    pub hittables: Vec<Hittable>
+    pub hittables: Vec<Hittable>,
+    pub lights: Vec<Light>
 }
 
 impl World {
@@ -52,7 +366,8 @@ impl World {
 fn two_spheres_scene() -> World {
     let mut world = World {
         materials: Vec::new(),
-        hittables: Vec::new()
+        hittables: Vec::new(),
+        lights: Vec::new()
     };
 
     let ground_material = world.register_material(Material::Lambertian { albedo: Texture::Checker(Color::new(0.2, 0.3, 0.1), Color::new(0.9, 0.9, 0.9)) });
@@ -65,7 +380,8 @@ fn two_spheres_scene() -> World {
 fn two_perlin_spheres_scene() -> World {
     let mut world = World {
         materials: Vec::new(),
-        hittables: Vec::new()
+        hittables: Vec::new(),
+        lights: Vec::new()
     };
 
     let ground_material = world.register_material(Material::Lambertian { albedo: Texture::Noise(Perlin::new(), 4.0) });
@@ -78,11 +394,17 @@ fn two_perlin_spheres_scene() -> World {
 fn earth_scene() -> World {
     let mut world = World {
         materials: Vec::new(),
-        hittables: Vec::new()
+        hittables: Vec::new(),
+        lights: Vec::new()
     };
 
-    let earth_texture = Texture::load_image("textures/earthmap.jpg");
-    let earth_material = world.register_material(Material::Lambertian { albedo: earth_texture });
+    // Longitude wraps around the sphere, so filtering across the seam repeats the image, while latitude stops at
+    // the poles
+    let earth_texture = Texture::load_image("textures/earthmap.jpg").with_filter(Filter::Bilinear).with_wrap(Wrap::Repeat, Wrap::Clamp);
+    let earth_surface = world.register_material(Material::Lambertian { albedo: earth_texture });
+    // Brighter land reads as higher than the dark oceans
+    let earth_bumps = Texture::load_image("textures/earthmap.jpg").with_filter(Filter::Bicubic).with_wrap(Wrap::Repeat, Wrap::Clamp);
+    let earth_material = world.register_material(Material::BumpMap { base: earth_surface, map: earth_bumps, scale: 0.01 });
     world.hittables.push(Hittable::Sphere { mat_handle: earth_material, center: Point3::new(0.0, 0.0, 0.0), radius: 2.0 });
     
     world
@@ -91,14 +413,15 @@ fn earth_scene() -> World {
 fn simple_light_scene() -> World {
     let mut world = World {
         materials: Vec::new(),
-        hittables: Vec::new()
+        hittables: Vec::new(),
+        lights: Vec::new()
     };
 
     let ground_material = world.register_material(Material::Lambertian { albedo: Texture::Noise(Perlin::new(), 4.0) });
     world.hittables.push(Hittable::Sphere { mat_handle: ground_material, center: Point3::new(0.0, -1000.0, 0.0), radius: 1000.0 });
     world.hittables.push(Hittable::Sphere { mat_handle: ground_material, center: Point3::new(0.0, 2.0, 0.0), radius: 2.0 });
 
-    let diff_light = world.register_material(Material::DiffuseLight { emit: Texture::SolidColor(Color::new(4.0, 4.0, 4.0)) });
+    let diff_light = world.register_material(Material::DiffuseLight { emit: Texture::SolidColor(Color::new(4.0, 4.0, 4.0)), two_sided: true });
     world.hittables.push(Hittable::XYRect { mat_handle: diff_light, x0: 3.0, x1: 5.0, y0: 1.0, y1: 3.0, k: -2.0 });
 
     world
@@ -107,17 +430,18 @@ fn simple_light_scene() -> World {
 fn cornell_box_scene() -> World {
     let mut world = World {
         materials: Vec::new(),
-        hittables: Vec::new()
+        hittables: Vec::new(),
+        lights: Vec::new()
     };
 
     let red = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.65, 0.05, 0.05)) });
     let white = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.73, 0.73, 0.73)) });
     let green = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.12, 0.45, 0.15)) });
-    let light = world.register_material(Material::DiffuseLight { emit: Texture::SolidColor(Color::new(15.0, 15.0, 15.0)) });
+    let light = world.register_material(Material::DiffuseLight { emit: Texture::SolidColor(Color::new(15.0, 15.0, 15.0)), two_sided: false });
 
     world.hittables.push(Hittable::YZRect { mat_handle: green, y0: 0.0,     y1: 555.0, z0: 0.0,     z1: 555.0, k: 555.0 });
     world.hittables.push(Hittable::YZRect { mat_handle: red,   y0: 0.0,     y1: 555.0, z0: 0.0,     z1: 555.0, k: 0.0 });
-    world.hittables.push(Hittable::XZRect { mat_handle: light, x0: 213.0,   x1: 343.0, z0: 227.0,   z1: 332.0, k: 554.0 });
+    world.hittables.push(Hittable::new_flip_face(Hittable::XZRect { mat_handle: light, x0: 213.0,   x1: 343.0, z0: 227.0,   z1: 332.0, k: 554.0 }));
     world.hittables.push(Hittable::XZRect { mat_handle: white, x0: 0.0,     x1: 555.0, z0: 0.0,     z1: 555.0, k: 0.0 });
     world.hittables.push(Hittable::XZRect { mat_handle: white, x0: 0.0,     x1: 555.0, z0: 0.0,     z1: 555.0, k: 555.0 });
     world.hittables.push(Hittable::XYRect { mat_handle: white, x0: 0.0,     x1: 555.0, y0: 0.0,     y1: 555.0, k: 555.0 });
@@ -138,17 +462,18 @@ fn cornell_box_scene() -> World {
 fn cornell_box_smoke_scene() -> World {
     let mut world = World {
         materials: Vec::new(),
-        hittables: Vec::new()
+        hittables: Vec::new(),
+        lights: Vec::new()
     };
 
     let red = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.65, 0.05, 0.05)) });
     let white = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.73, 0.73, 0.73)) });
     let green = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.12, 0.45, 0.15)) });
-    let light = world.register_material(Material::DiffuseLight { emit: Texture::SolidColor(Color::new(7.0, 7.0, 7.0)) });
+    let light = world.register_material(Material::DiffuseLight { emit: Texture::SolidColor(Color::new(7.0, 7.0, 7.0)), two_sided: false });
 
     world.hittables.push(Hittable::YZRect { mat_handle: green, y0: 0.0,     y1: 555.0, z0: 0.0,     z1: 555.0, k: 555.0 });
     world.hittables.push(Hittable::YZRect { mat_handle: red,   y0: 0.0,     y1: 555.0, z0: 0.0,     z1: 555.0, k: 0.0 });
-    world.hittables.push(Hittable::XZRect { mat_handle: light, x0: 113.0,   x1: 443.0, z0: 127.0,   z1: 432.0, k: 554.0 });
+    world.hittables.push(Hittable::new_flip_face(Hittable::XZRect { mat_handle: light, x0: 113.0,   x1: 443.0, z0: 127.0,   z1: 432.0, k: 554.0 }));
     world.hittables.push(Hittable::XZRect { mat_handle: white, x0: 0.0,     x1: 555.0, z0: 0.0,     z1: 555.0, k: 0.0 });
     world.hittables.push(Hittable::XZRect { mat_handle: white, x0: 0.0,     x1: 555.0, z0: 0.0,     z1: 555.0, k: 555.0 });
     world.hittables.push(Hittable::XYRect { mat_handle: white, x0: 0.0,     x1: 555.0, y0: 0.0,     y1: 555.0, k: 555.0 });
@@ -173,7 +498,8 @@ fn cornell_box_smoke_scene() -> World {
 fn final_scene() -> World {
     let mut world = World {
         materials: Vec::new(),
-        hittables: Vec::new()
+        hittables: Vec::new(),
+        lights: Vec::new()
     };
 
     let mut boxes1 = Vec::new();
@@ -197,28 +523,32 @@ fn final_scene() -> World {
 
     world.hittables.push(Hittable::new_bvh_node(&boxes1, 0, boxes1.len(), 0.0, 1.0));
 
-    let light = world.register_material(Material::DiffuseLight { emit: Texture::SolidColor(Color::new(7.0, 7.0, 7.0)) });
-    world.hittables.push(Hittable::XZRect { mat_handle: light, x0: 123.0, x1: 423.0, z0: 147.0, z1: 412.0, k: 554.0 });
+    let light = world.register_material(Material::DiffuseLight { emit: Texture::SolidColor(Color::new(7.0, 7.0, 7.0)), two_sided: false });
+    world.hittables.push(Hittable::new_flip_face(Hittable::XZRect { mat_handle: light, x0: 123.0, x1: 423.0, z0: 147.0, z1: 412.0, k: 554.0 }));
 
     let center_1 = Point3::new(400.0, 400.0, 200.0);
     let center_2 = center_1 + Vector3::new(30.0, 0.0, 0.0);
     let moving_sphere_material = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.7, 0.3, 0.1)) });
     world.hittables.push(Hittable::MovingSphere { mat_handle: moving_sphere_material, center_0: center_1, center_1: center_2, time_0: 0.0, time_1: 1.0, radius: 50.0 });
 
-    let dielectric = world.register_material(Material::Dielectric { ir: 1.5 });
+    let dielectric = world.register_material(Material::Dielectric { ir: 1.5, absorption: Color::new(0.0, 0.0, 0.0), tint: Texture::Scalar(1.0) });
     world.hittables.push(Hittable::Sphere { mat_handle: dielectric, center: Point3::new(260.0, 150.0, 45.0), radius: 50.0 });
 
-    let metal = world.register_material(Material::Metal { albedo: Color::new(0.8, 0.8, 0.9), fuzz: 1.0 });
+    let metal = world.register_material(Material::Metal { albedo: Texture::SolidColor(Color::new(0.8, 0.8, 0.9)), fuzz: Texture::Scalar(1.0) });
     world.hittables.push(Hittable::Sphere { mat_handle: metal, center: Point3::new(0.0, 150.0, 145.0), radius: 50.0 });
 
-    let boundary = Hittable::Sphere { mat_handle: dielectric, center: Point3::new(360.0, 150.0, 145.0), radius: 70.0 };
-    world.hittables.push(boundary.clone());
-    let phase = world.register_material(Material::Isotropic { albedo: Texture::SolidColor(Color::new(0.2, 0.4, 0.9)) });
-    world.hittables.push(Hittable::new_constant_medium(boundary, 0.2, phase));
+    let smoke_filled = world.register_material(Material::MediumInterface {
+        base: Some(dielectric),
+        interior: Medium::new_homogeneous(Color::new(0.2, 0.2, 0.2), Color::new(0.2, 0.4, 0.9))
+    });
+    world.hittables.push(Hittable::Sphere { mat_handle: smoke_filled, center: Point3::new(360.0, 150.0, 145.0), radius: 70.0 });
 
-    let boundary = Hittable::Sphere { mat_handle: dielectric, center: Point3::new(0.0, 0.0, 0.0), radius: 5000.0 };
-    let phase = world.register_material(Material::Isotropic { albedo: Texture::SolidColor(Color::new(1.0, 1.0, 1.0)) });
-    world.hittables.push(Hittable::new_constant_medium(boundary, 0.0001, phase));
+    // Thin mist around the whole scene, camera included
+    let mist = world.register_material(Material::MediumInterface {
+        base: None,
+        interior: Medium::new_homogeneous(Color::new(0.0001, 0.0001, 0.0001), Color::new(1.0, 1.0, 1.0))
+    });
+    world.hittables.push(Hittable::Sphere { mat_handle: mist, center: Point3::new(0.0, 0.0, 0.0), radius: 5000.0 });
 
     let emat = world.register_material(Material::Lambertian { albedo: Texture::load_image("textures/earthmap.jpg") });
     world.hittables.push(Hittable::Sphere { mat_handle: emat, center: Point3::new(400.0, 200.0, 400.0), radius: 100.0 });
@@ -245,7 +575,8 @@ fn final_scene() -> World {
 fn random_scene() -> World {
     let mut world = World {
         materials: Vec::new(),
-        hittables: Vec::new()
+        hittables: Vec::new(),
+        lights: Vec::new()
     };
 
     let ground_material = world.register_material(Material::Lambertian { albedo: Texture::Checker(Color::new(0.2, 0.5, 0.5), Color::new(0.9, 0.9, 0.9)) });
@@ -266,207 +597,1216 @@ fn random_scene() -> World {
                 } else if choose_mat < 0.95 {
                     let albedo = Color::random_range(0.5, 1.0); 
                     let fuzz = random_double_range(0.0, 0.5);
-                    let sphere_material = world.register_material(Material::Metal { albedo, fuzz });
+                    let sphere_material = world.register_material(Material::Metal { albedo: Texture::SolidColor(albedo), fuzz: Texture::Scalar(fuzz) });
                     world.hittables.push(Hittable::Sphere { mat_handle: sphere_material, center, radius: 0.2 });
                 } else {
-                    let sphere_material = world.register_material(Material::Dielectric { ir: 1.5 });
+                    let sphere_material = world.register_material(Material::Dielectric { ir: 1.5, absorption: Color::new(0.0, 0.0, 0.0), tint: Texture::Scalar(1.0) });
                     world.hittables.push(Hittable::Sphere { mat_handle: sphere_material, center, radius: 0.2 });
                 }
             }
         }
     }
 
-    let material1 = world.register_material(Material::Dielectric { ir: 1.5 });
+    let material1 = world.register_material(Material::Dielectric { ir: 1.5, absorption: Color::new(0.0, 0.0, 0.0), tint: Texture::Scalar(1.0) });
     world.hittables.push(Hittable::Sphere { mat_handle: material1, center: Point3::new(0.0, 1.0, 0.0), radius: 1.0 });
 
     let material2 = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.4, 0.2, 0.1)) });
     world.hittables.push(Hittable::Sphere { mat_handle: material2, center: Point3::new(-4.0, 1.0, 0.0), radius: 1.0 });
 
-    let material3 = world.register_material(Material::Metal { albedo: Color::new(0.7, 0.6, 0.5), fuzz: 0.0 });
+    let material3 = world.register_material(Material::Metal { albedo: Texture::SolidColor(Color::new(0.7, 0.6, 0.5)), fuzz: Texture::Scalar(0.0) });
     world.hittables.push(Hittable::Sphere { mat_handle: material3, center: Point3::new(4.0, 1.0, 0.0), radius: 1.0 });
 
     world
 }
 
-struct PixelChunk {
-    pub x: usize,
-    pub y: usize
-}
+fn environment_scene() -> World {
+    let mut world = World {
+        materials: Vec::new(),
+        hittables: Vec::new(),
+        lights: Vec::new()
+    };
 
-struct Scene {
-    pub aspect_ratio: f64,
-    pub image_width: usize,
-    pub samples_per_pixel: usize,
-    pub background: Color,
-    pub look_from: Point3,
-    pub look_at: Point3,
-    pub vfov: f64,
-    pub world: std::sync::Arc<World>
-}
+    let ground_material = world.register_material(Material::Lambertian { albedo: Texture::Checker(Color::new(0.2, 0.3, 0.1), Color::new(0.9, 0.9, 0.9)) });
+    world.hittables.push(Hittable::Sphere { mat_handle: ground_material, center: Point3::new(0.0, -1000.0, 0.0), radius: 1000.0 });
 
-fn main() {
-    // Image
-    let thread_count = 10; // Find maximum thread count for CPU
-    let max_depth = 50;
-    let vup = Vector3::new(0.0, 1.0, 0.0);
-    let dist_to_focus = 10.0; 
+    let glass = world.register_material(Material::new_tinted_dielectric(1.5, Color::new(0.4, 0.8, 0.9), 1.0));
+    world.hittables.push(Hittable::Sphere { mat_handle: glass, center: Point3::new(0.0, 1.0, 0.0), radius: 1.0 });
 
-    let scene = match 7 {
+    let diffuse = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.4, 0.2, 0.1)) });
+    world.hittables.push(Hittable::Sphere { mat_handle: diffuse, center: Point3::new(-4.0, 1.0, 0.0), radius: 1.0 });
 
-        0 => {
-            let world = Arc::new(random_scene());
+    let metal = world.register_material(Material::Metal { albedo: Texture::SolidColor(Color::new(0.7, 0.6, 0.5)), fuzz: Texture::Scalar(0.0) });
+    world.hittables.push(Hittable::Sphere { mat_handle: metal, center: Point3::new(4.0, 1.0, 0.0), radius: 1.0 });
 
-            // Camera
-            let look_from = Point3::new(13.0, 2.0, 3.0);
-            let look_at = Point3::new(0.0, 0.0, 0.0);
+    world
+}
 
-            Scene {
-                aspect_ratio: 16.0 / 9.0,
-                image_width: 400,
-                samples_per_pixel: 100,
-                background: Color::new(0.7, 0.8, 1.0),
-                look_from,
-                look_at,
-                vfov: 20.0,
-                world
-            }
-        },
-        1 => {
-            let world = Arc::new(two_spheres_scene());
+fn delta_lights_scene() -> World {
+    let mut world = World {
+        materials: Vec::new(),
+        hittables: Vec::new(),
+        lights: Vec::new()
+    };
 
-            // Camera
-            let look_from = Point3::new(13.0, 2.0, 3.0);
-            let look_at = Point3::new(0.0, 0.0, 0.0);
+    let ground_material = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.5, 0.5, 0.5)) });
+    world.hittables.push(Hittable::Sphere { mat_handle: ground_material, center: Point3::new(0.0, -1000.0, 0.0), radius: 1000.0 });
 
-            Scene {
-                aspect_ratio: 16.0 / 9.0,
-                image_width: 400,
-                samples_per_pixel: 100,
-                background: Color::new(0.7, 0.8, 1.0),
-                look_from,
-                look_at,
-                vfov: 20.0,
-                world
-            }
-        },
-        2 => {
-            let world = Arc::new(two_perlin_spheres_scene());
+    let red = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.65, 0.05, 0.05)) });
+    world.hittables.push(Hittable::Sphere { mat_handle: red, center: Point3::new(-4.0, 1.0, 0.0), radius: 1.0 });
 
-            // Camera
-            let look_from = Point3::new(13.0, 2.0, 3.0);
-            let look_at = Point3::new(0.0, 0.0, 0.0);
+    let white = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.73, 0.73, 0.73)) });
+    world.hittables.push(Hittable::Sphere { mat_handle: white, center: Point3::new(0.0, 1.0, 0.0), radius: 1.0 });
 
-            Scene {
-                aspect_ratio: 16.0 / 9.0,
-                image_width: 400,
-                samples_per_pixel: 100,
-                background: Color::new(0.7, 0.8, 1.0),
-                look_from,
-                look_at,
-                vfov: 20.0,
-                world
-            }
-        },
-        3 => {
-            let world = Arc::new(earth_scene());
+    let metal = world.register_material(Material::Metal { albedo: Texture::SolidColor(Color::new(0.7, 0.6, 0.5)), fuzz: Texture::Scalar(0.2) });
+    world.hittables.push(Hittable::Sphere { mat_handle: metal, center: Point3::new(4.0, 1.0, 0.0), radius: 1.0 });
 
-            // Camera
-            let look_from = Point3::new(13.0, 2.0, 3.0);
-            let look_at = Point3::new(0.0, 0.0, 0.0);
+    world.lights.push(Light::Point { position: Point3::new(-4.0, 4.0, 2.0), intensity: Color::new(20.0, 16.0, 12.0) });
+    world.lights.push(Light::new_spot(Point3::new(0.0, 6.0, 3.0), Point3::new(0.0, 0.0, 0.0), Color::new(60.0, 60.0, 80.0), 30.0, 20.0));
+    world.lights.push(Light::new_directional(Vector3::new(-1.0, -1.0, -0.5), Color::new(0.3, 0.3, 0.25)));
 
-            Scene {
-                aspect_ratio: 16.0 / 9.0,
-                image_width: 400,
-                samples_per_pixel: 100,
-                background: Color::new(0.7, 0.8, 1.0),
-                look_from,
-                look_at,
-                vfov: 20.0,
-                world
-            }
-        },
-        4 => {
-            let world = Arc::new(simple_light_scene());
+    world
+}
 
-            // Camera
-            let look_from = Point3::new(26.0, 3.0, 6.0);
-            let look_at = Point3::new(0.0, 2.0, 0.0);
+fn conductors_scene() -> World {
+    let mut world = World {
+        materials: Vec::new(),
+        hittables: Vec::new(),
+        lights: Vec::new()
+    };
 
-            Scene {
-                aspect_ratio: 16.0 / 9.0,
-                image_width: 400,
-                samples_per_pixel: 100,
-                background: Color::new(0.0, 0.0, 0.0),
-                look_from,
-                look_at,
-                vfov: 20.0,
-                world
-            }
-        },
-        5 => {
-            let world = Arc::new(cornell_box_scene());
+    let ground_material = world.register_material(Material::Lambertian { albedo: Texture::Checker(Color::new(0.2, 0.3, 0.1), Color::new(0.9, 0.9, 0.9)) });
+    world.hittables.push(Hittable::Sphere { mat_handle: ground_material, center: Point3::new(0.0, -1000.0, 0.0), radius: 1000.0 });
 
-            // Camera
-            let look_from = Point3::new(278.0, 278.0, -800.0);
-            let look_at = Point3::new(278.0, 278.0, 0.0);
+    let presets = [ConductorPreset::Gold, ConductorPreset::Copper, ConductorPreset::Silver, ConductorPreset::Aluminum];
+    for (i, preset) in presets.iter().enumerate() {
+        let z = -4.5 + 3.0 * i as f64;
 
-            Scene {
-                aspect_ratio: 1.0,
-                image_width: 600,
-                samples_per_pixel: 200,
-                background: Color::new(0.0, 0.0, 0.0),
-                look_from,
-                look_at,
-                vfov: 40.0,
-                world
-            }
-        },
-        6 => {
-            let world = Arc::new(cornell_box_smoke_scene());
+        let polished = world.register_material(Material::new_conductor(*preset, 0.0, 0.0));
+        world.hittables.push(Hittable::Sphere { mat_handle: polished, center: Point3::new(2.0, 1.0, z), radius: 1.0 });
 
-            // Camera
-            let look_from = Point3::new(278.0, 278.0, -800.0);
-            let look_at = Point3::new(278.0, 278.0, 0.0);
+        let rough = world.register_material(Material::new_conductor(*preset, 0.3, 0.3));
+        world.hittables.push(Hittable::Sphere { mat_handle: rough, center: Point3::new(-1.0, 1.0, z), radius: 1.0 });
 
-            Scene {
-                aspect_ratio: 1.0,
-                image_width: 600,
-                samples_per_pixel: 40,
-                background: Color::new(0.0, 0.0, 0.0),
-                look_from,
-                look_at,
-                vfov: 40.0,
-                world
-            }
-        },
-        7 => {
-            let world = Arc::new(final_scene());
+        let brushed = world.register_material(Material::new_conductor(*preset, 0.1, 0.5));
+        world.hittables.push(Hittable::Sphere { mat_handle: brushed, center: Point3::new(-4.0, 1.0, z), radius: 1.0 });
+    }
 
-            // Camera
-            let look_from = Point3::new(478.0, 278.0, -600.0);
-            let look_at = Point3::new(278.0, 278.0, 0.0);
+    world
+}
 
-            Scene {
-                aspect_ratio: 1.0,
-                image_width: 800,
-                samples_per_pixel: 2000,
-                background: Color::new(0.0, 0.0, 0.0),
-                look_from,
-                look_at,
-                vfov: 40.0,
-                world
-            }
-        },
+fn rough_dielectrics_scene() -> World {
+    let mut world = World {
+        materials: Vec::new(),
+        hittables: Vec::new(),
+        lights: Vec::new()
+    };
 
-        _ => {
-            panic!("Unsupported scene selected")
+    let ground_material = world.register_material(Material::Lambertian { albedo: Texture::Checker(Color::new(0.2, 0.3, 0.1), Color::new(0.9, 0.9, 0.9)) });
+    world.hittables.push(Hittable::Sphere { mat_handle: ground_material, center: Point3::new(0.0, -1000.0, 0.0), radius: 1000.0 });
+
+    for (i, roughness) in [0.0, 0.1, 0.3, 0.6].iter().enumerate() {
+        let glass = world.register_material(Material::RoughDielectric { ir: 1.5, roughness: Texture::Scalar(*roughness), absorption: Color::new(0.0, 0.0, 0.0), tint: Texture::Scalar(1.0) });
+        world.hittables.push(Hittable::Sphere { mat_handle: glass, center: Point3::new(0.0, 1.0, -4.5 + 3.0 * i as f64), radius: 1.0 });
+    }
+
+    // Frosted glass tinted green at its surface
+    let frosted = world.register_material(Material::RoughDielectric { ir: 1.5, roughness: Texture::Scalar(0.3), absorption: Color::new(0.0, 0.0, 0.0), tint: Texture::SolidColor(Color::new(0.6, 0.9, 0.6)) });
+    world.hittables.push(Hittable::Sphere { mat_handle: frosted, center: Point3::new(-4.0, 1.0, 0.0), radius: 1.0 });
+
+    world
+}
+
+fn principled_scene() -> World {
+    let mut world = World {
+        materials: Vec::new(),
+        hittables: Vec::new(),
+        lights: Vec::new()
+    };
+
+    let ground_material = world.register_material(Material::Lambertian { albedo: Texture::Checker(Color::new(0.2, 0.3, 0.1), Color::new(0.9, 0.9, 0.9)) });
+    world.hittables.push(Hittable::Sphere { mat_handle: ground_material, center: Point3::new(0.0, -1000.0, 0.0), radius: 1000.0 });
+
+    let mut plastic = Principled::new(Texture::SolidColor(Color::new(0.7, 0.1, 0.1)));
+    plastic.roughness = Texture::SolidColor(Color::new(0.4, 0.4, 0.4));
+
+    let mut brushed = Principled::new(Texture::SolidColor(Color::new(0.9, 0.7, 0.4)));
+    brushed.metallic = Texture::SolidColor(Color::new(1.0, 1.0, 1.0));
+    brushed.roughness = Texture::Checker(Color::new(0.1, 0.1, 0.1), Color::new(0.6, 0.6, 0.6));
+
+    let mut car_paint = Principled::new(Texture::SolidColor(Color::new(0.05, 0.1, 0.5)));
+    car_paint.clearcoat = Texture::SolidColor(Color::new(1.0, 1.0, 1.0));
+
+    let mut velvet = Principled::new(Texture::SolidColor(Color::new(0.3, 0.05, 0.3)));
+    velvet.roughness = Texture::SolidColor(Color::new(1.0, 1.0, 1.0));
+    velvet.sheen = Texture::SolidColor(Color::new(1.0, 1.0, 1.0));
+
+    let mut glass = Principled::new(Texture::SolidColor(Color::new(0.8, 1.0, 0.8)));
+    glass.roughness = Texture::SolidColor(Color::new(0.1, 0.1, 0.1));
+    glass.transmission = Texture::SolidColor(Color::new(1.0, 1.0, 1.0));
+
+    for (i, principled) in vec![plastic, brushed, car_paint, velvet, glass].into_iter().enumerate() {
+        let material = world.register_material(Material::Principled(Box::new(principled)));
+        world.hittables.push(Hittable::Sphere { mat_handle: material, center: Point3::new(0.0, 1.0, -6.0 + 3.0 * i as f64), radius: 1.0 });
+    }
+
+    world
+}
+
+fn dispersion_scene() -> World {
+    let mut world = World {
+        materials: Vec::new(),
+        hittables: Vec::new(),
+        lights: Vec::new()
+    };
+
+    let white = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.73, 0.73, 0.73)) });
+    world.hittables.push(Hittable::XZRect { mat_handle: white, x0: -10.0, x1: 10.0, z0: -10.0, z1: 10.0, k: 0.0 });
+
+    let light = world.register_material(Material::DiffuseLight { emit: Texture::SolidColor(Color::new(40.0, 40.0, 40.0)), two_sided: true });
+    world.hittables.push(Hittable::XZRect { mat_handle: light, x0: -0.75, x1: 0.75, z0: -0.75, z1: 0.75, k: 6.0 });
+
+    let flint = world.register_material(Material::DispersiveDielectric { dispersion: Dispersion::dense_flint(), absorption: Color::new(0.0, 0.0, 0.0), tint: Texture::Scalar(1.0) });
+    world.hittables.push(Hittable::Sphere { mat_handle: flint, center: Point3::new(0.0, 0.8, -2.7), radius: 0.8 });
+
+    let diamond = world.register_material(Material::DispersiveDielectric { dispersion: Dispersion::diamond(), absorption: Color::new(0.0, 0.0, 0.0), tint: Texture::Scalar(1.0) });
+    world.hittables.push(Hittable::Sphere { mat_handle: diamond, center: Point3::new(0.0, 0.8, -0.9), radius: 0.8 });
+
+    // Crown glass block turned to the light, whose low dispersion barely splits it
+    let bk7 = world.register_material(Material::DispersiveDielectric { dispersion: Dispersion::bk7(), absorption: Color::new(0.0, 0.0, 0.0), tint: Texture::Scalar(1.0) });
+    let block = Hittable::new_box(Point3::new(-0.6, 0.0, -0.6), Point3::new(0.6, 1.2, 0.6), bk7);
+    let block = Hittable::new_rotate_y(45.0, block);
+    world.hittables.push(Hittable::Translate { offset: Vector3::new(0.0, 0.01, 0.9), ptr: Box::new(block) });
+
+    // Fused silica from a two term Cauchy fit
+    let silica = world.register_material(Material::DispersiveDielectric { dispersion: Dispersion::Cauchy { a: 1.4580, b: 0.00354 }, absorption: Color::new(0.0, 0.0, 0.0), tint: Texture::Scalar(1.0) });
+    world.hittables.push(Hittable::Sphere { mat_handle: silica, center: Point3::new(0.0, 0.8, 2.7), radius: 0.8 });
+
+    world
+}
+
+fn spectral_lights_scene() -> World {
+    let mut world = World {
+        materials: Vec::new(),
+        hittables: Vec::new(),
+        lights: Vec::new()
+    };
+
+    let white = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.73, 0.73, 0.73)) });
+    world.hittables.push(Hittable::XZRect { mat_handle: white, x0: -10.0, x1: 10.0, z0: -10.0, z1: 10.0, k: 0.0 });
+
+    let spectra = [Spectrum::d65(), Spectrum::illuminant_a(), Spectrum::f2()];
+    for (i, spectrum) in spectra.iter().enumerate() {
+        let z = -3.0 + 3.0 * i as f64;
+
+        let light = world.register_material(Material::new_spectral_light(spectrum.clone(), 4.0, true));
+        world.hittables.push(Hittable::XZRect { mat_handle: light, x0: -0.5, x1: 0.5, z0: z - 0.5, z1: z + 0.5, k: 3.0 });
+
+        world.hittables.push(Hittable::Sphere { mat_handle: white, center: Point3::new(0.0, 1.0, z), radius: 1.0 });
+    }
+
+    world
+}
+
+fn blackbody_scene() -> World {
+    let mut world = World {
+        materials: Vec::new(),
+        hittables: Vec::new(),
+        lights: Vec::new()
+    };
+
+    let white = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.73, 0.73, 0.73)) });
+    world.hittables.push(Hittable::XZRect { mat_handle: white, x0: -10.0, x1: 10.0, z0: -10.0, z1: 10.0, k: 0.0 });
+
+    // One-sided panels facing +x, from candle light to clear blue sky, each putting out 30 lumens over its 2 by 0.8 area
+    let temperatures = [1900.0, 2700.0, 4000.0, 6500.0, 10000.0];
+    for (i, temperature) in temperatures.iter().enumerate() {
+        let z = -4.0 + 2.0 * i as f64;
+
+        let light = world.register_material(Material::new_blackbody_light_with_power(*temperature, 30.0, 2.0 * 0.8, false));
+        world.hittables.push(Hittable::YZRect { mat_handle: light, y0: 0.5, y1: 2.5, z0: z - 0.4, z1: z + 0.4, k: -1.0 });
+
+        world.hittables.push(Hittable::Sphere { mat_handle: white, center: Point3::new(0.5, 0.6, z), radius: 0.6 });
+    }
+
+    world
+}
+
+fn light_visibility_scene() -> World {
+    let mut world = World {
+        materials: Vec::new(),
+        hittables: Vec::new(),
+        lights: Vec::new()
+    };
+
+    let white = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.73, 0.73, 0.73)) });
+    world.hittables.push(Hittable::XZRect { mat_handle: white, x0: -10.0, x1: 10.0, z0: -10.0, z1: 10.0, k: 0.0 });
+
+    let mirror = world.register_material(Material::Metal { albedo: Texture::SolidColor(Color::new(0.9, 0.9, 0.9)), fuzz: Texture::Scalar(0.0) });
+    world.hittables.push(Hittable::Sphere { mat_handle: mirror, center: Point3::new(0.0, 1.2, 0.0), radius: 1.2 });
+
+    // Both panels face the camera and light the floor, but the left one only shows up in the mirror and the
+    // right one only to the camera
+    let light = world.register_material(Material::DiffuseLight { emit: Texture::SolidColor(Color::new(6.0, 6.0, 6.0)), two_sided: false });
+    let left = Hittable::YZRect { mat_handle: light, y0: 0.5, y1: 3.0, z0: 2.0, z1: 3.5, k: -3.0 };
+    world.hittables.push(Hittable::new_hidden(left, true, false));
+    let right = Hittable::YZRect { mat_handle: light, y0: 0.5, y1: 3.0, z0: -3.5, z1: -2.0, k: -3.0 };
+    world.hittables.push(Hittable::new_hidden(right, false, true));
+
+    world
+}
+
+fn mix_materials_scene() -> World {
+    let mut world = World {
+        materials: Vec::new(),
+        hittables: Vec::new(),
+        lights: Vec::new()
+    };
+
+    let ground = world.register_material(Material::Lambertian { albedo: Texture::Checker(Color::new(0.2, 0.3, 0.1), Color::new(0.9, 0.9, 0.9)) });
+    world.hittables.push(Hittable::Sphere { mat_handle: ground, center: Point3::new(0.0, -1000.0, 0.0), radius: 1000.0 });
+
+    // Red paint flaking off to reveal rust, masked by noise
+    let rust = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.35, 0.12, 0.05)) });
+    let red = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.7, 0.05, 0.05)) });
+    let paint = world.register_material(Material::Coated { base: red, ir: 1.5, tint: Color::new(1.0, 1.0, 1.0) });
+    let rusty_paint = world.register_material(Material::Mix { first: paint, second: rust, amount: Texture::Noise(Perlin::new(), 3.0) });
+    world.hittables.push(Hittable::Sphere { mat_handle: rusty_paint, center: Point3::new(0.0, 1.0, -2.5), radius: 1.0 });
+
+    let gold = world.register_material(Material::new_conductor(ConductorPreset::Gold, 0.4, 0.4));
+    let coated_gold = world.register_material(Material::Coated { base: gold, ir: 1.5, tint: Color::new(1.0, 1.0, 1.0) });
+    world.hittables.push(Hittable::Sphere { mat_handle: coated_gold, center: Point3::new(0.0, 1.0, 0.0), radius: 1.0 });
+
+    // Half blue paint, half brushed metal
+    let blue = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.05, 0.1, 0.5)) });
+    let steel = world.register_material(Material::Metal { albedo: Texture::SolidColor(Color::new(0.8, 0.8, 0.8)), fuzz: Texture::Scalar(0.2) });
+    let blend = world.register_material(Material::new_mix(blue, steel, 0.5));
+    let car_paint = world.register_material(Material::Coated { base: blend, ir: 1.5, tint: Color::new(0.95, 0.95, 1.0) });
+    world.hittables.push(Hittable::Sphere { mat_handle: car_paint, center: Point3::new(0.0, 1.0, 2.5), radius: 1.0 });
+
+    world
+}
+
+fn normal_mapping_scene() -> World {
+    let mut world = World {
+        materials: Vec::new(),
+        hittables: Vec::new(),
+        lights: Vec::new()
+    };
+
+    let floor = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.6, 0.55, 0.5)) });
+    let tiles = world.register_material(Material::NormalMap { base: floor, map: Texture::load_image("textures/tiles_normal.png"), strength: 1.0 });
+    world.hittables.push(Hittable::XZRect { mat_handle: tiles, x0: -4.0, x1: 4.0, z0: -4.0, z1: 4.0, k: 0.0 });
+
+    let white = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.73, 0.73, 0.73)) });
+    let stone = world.register_material(Material::BumpMap { base: white, map: Texture::Noise(Perlin::new(), 4.0), scale: 0.02 });
+    world.hittables.push(Hittable::Sphere { mat_handle: stone, center: Point3::new(0.0, 1.0, -1.5), radius: 1.0 });
+
+    let metal = world.register_material(Material::Metal { albedo: Texture::SolidColor(Color::new(0.8, 0.8, 0.85)), fuzz: Texture::Scalar(0.05) });
+    let hammered = world.register_material(Material::BumpMap { base: metal, map: Texture::Noise(Perlin::new(), 1.0), scale: 0.01 });
+    world.hittables.push(Hittable::Sphere { mat_handle: hammered, center: Point3::new(0.0, 1.0, 1.5), radius: 1.0 });
+
+    // Grazing light brings out the relief
+    world.lights.push(Light::new_directional(Vector3::new(-1.0, -0.4, 0.6), Color::new(3.0, 2.8, 2.5)));
+
+    world
+}
+
+fn alpha_mask_scene() -> World {
+    let mut world = World {
+        materials: Vec::new(),
+        hittables: Vec::new(),
+        lights: Vec::new()
+    };
+
+    let ground = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.6, 0.55, 0.45)) });
+    world.hittables.push(Hittable::XZRect { mat_handle: ground, x0: -10.0, x1: 10.0, z0: -10.0, z1: 10.0, k: 0.0 });
+
+    // Leaf cards cut out by the texture's alpha channel
+    let leaf_texture = world.register_material(Material::Lambertian { albedo: Texture::load_image("textures/leaf.png") });
+    let leaf = world.register_material(Material::AlphaMask { base: leaf_texture, mask: Texture::load_image("textures/leaf.png") });
+    for i in 0..12 {
+        let card = Hittable::XYRect { mat_handle: leaf, x0: -0.6, x1: 0.6, y0: 0.0, y1: 1.2, k: 0.0 };
+        let card = Hittable::new_rotate_y(30.0 * i as f64, card);
+        let offset = Vector3::new(random_double_range(-2.0, 2.0), random_double_range(0.3, 2.0), random_double_range(-3.0, 1.0));
+        world.hittables.push(Hittable::Translate { offset, ptr: Box::new(card) });
+    }
+
+    // Procedural fence: the checker's black squares are holes
+    let wood = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.45, 0.3, 0.2)) });
+    let fence = world.register_material(Material::AlphaMask { base: wood, mask: Texture::Checker(Color::new(0.0, 0.0, 0.0), Color::new(1.0, 1.0, 1.0)) });
+    world.hittables.push(Hittable::YZRect { mat_handle: fence, y0: 0.0, y1: 2.5, z0: -5.0, z1: 5.0, k: -3.0 });
+
+    world.lights.push(Light::new_directional(Vector3::new(-1.0, -1.5, 0.8), Color::new(2.5, 2.4, 2.2)));
+
+    world
+}
+
+fn textured_parameters_scene() -> World {
+    let mut world = World {
+        materials: Vec::new(),
+        hittables: Vec::new(),
+        lights: Vec::new()
+    };
+
+    let ground = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.5, 0.5, 0.5)) });
+    world.hittables.push(Hittable::Sphere { mat_handle: ground, center: Point3::new(0.0, -1000.0, 0.0), radius: 1000.0 });
+
+    // Checker switching between polished and fuzzy patches
+    let metal = world.register_material(Material::Metal {
+        albedo: Texture::SolidColor(Color::new(0.8, 0.8, 0.8)),
+        fuzz: Texture::Checker(Color::new(0.0, 0.0, 0.0), Color::new(0.5, 0.5, 0.5))
+    });
+    world.hittables.push(Hittable::Sphere { mat_handle: metal, center: Point3::new(0.0, 1.0, -3.0), radius: 1.0 });
+
+    let (eta, k) = ConductorPreset::Copper.eta_k();
+    let copper = world.register_material(Material::Conductor { eta, k, roughness_u: Texture::Noise(Perlin::new(), 4.0), roughness_v: Texture::Noise(Perlin::new(), 4.0) });
+    world.hittables.push(Hittable::Sphere { mat_handle: copper, center: Point3::new(0.0, 1.0, -1.0), radius: 1.0 });
+
+    let glass = world.register_material(Material::Dielectric {
+        ir: 1.5,
+        absorption: Color::new(0.0, 0.0, 0.0),
+        tint: Texture::Checker(Color::new(1.0, 1.0, 1.0), Color::new(0.9, 0.5, 0.5))
+    });
+    world.hittables.push(Hittable::Sphere { mat_handle: glass, center: Point3::new(0.0, 1.0, 1.0), radius: 1.0 });
+
+    // Lamp whose emission strength follows a checker mask
+    let lamp = Material::new_blackbody_light(3000.0, 4.0, true).with_strength(Texture::Checker(Color::new(0.2, 0.2, 0.2), Color::new(1.0, 1.0, 1.0)));
+    let lamp = world.register_material(lamp);
+    world.hittables.push(Hittable::Sphere { mat_handle: lamp, center: Point3::new(0.0, 1.0, 3.0), radius: 1.0 });
+
+    world
+}
+
+fn subsurface_scene() -> World {
+    let mut world = World {
+        materials: Vec::new(),
+        hittables: Vec::new(),
+        lights: Vec::new()
+    };
+
+    let ground = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.4, 0.4, 0.4)) });
+    world.hittables.push(Hittable::XZRect { mat_handle: ground, x0: -10.0, x1: 10.0, z0: -10.0, z1: 10.0, k: 0.0 });
+
+    let wax = world.register_material(Material::Subsurface { albedo: Texture::SolidColor(Color::new(0.99, 0.95, 0.8)), mean_free_path: Color::new(0.3, 0.2, 0.1), ir: 1.45 });
+    world.hittables.push(Hittable::Sphere { mat_handle: wax, center: Point3::new(0.0, 1.0, -2.5), radius: 1.0 });
+
+    let skin = world.register_material(Material::Subsurface { albedo: Texture::SolidColor(Color::new(0.98, 0.85, 0.75)), mean_free_path: Color::new(0.4, 0.15, 0.08), ir: 1.4 });
+    world.hittables.push(Hittable::Sphere { mat_handle: skin, center: Point3::new(0.0, 1.0, 0.0), radius: 1.0 });
+
+    let marble = world.register_material(Material::Subsurface { albedo: Texture::SolidColor(Color::new(0.995, 0.995, 0.99)), mean_free_path: Color::new(0.2, 0.2, 0.25), ir: 1.5 });
+    world.hittables.push(Hittable::Sphere { mat_handle: marble, center: Point3::new(0.0, 1.0, 2.5), radius: 1.0 });
+
+    // Back light shining through the spheres
+    let light = world.register_material(Material::DiffuseLight { emit: Texture::SolidColor(Color::new(6.0, 6.0, 6.0)), two_sided: false });
+    world.hittables.push(Hittable::new_flip_face(Hittable::XZRect { mat_handle: light, x0: -3.0, x1: -1.0, z0: -4.0, z1: 4.0, k: 4.0 }));
+
+    world
+}
+
+fn fog_scene() -> World {
+    let mut world = World {
+        materials: Vec::new(),
+        hittables: Vec::new(),
+        lights: Vec::new()
+    };
+
+    let ground = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.5, 0.5, 0.5)) });
+    world.hittables.push(Hittable::XZRect { mat_handle: ground, x0: -20.0, x1: 20.0, z0: -20.0, z1: 20.0, k: 0.0 });
+
+    let white = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.73, 0.73, 0.73)) });
+    world.hittables.push(Hittable::Sphere { mat_handle: white, center: Point3::new(0.0, 1.0, 0.0), radius: 1.0 });
+
+    // Forward scattering fog surrounding the camera, so lights in front of it get a halo
+    let fog_phase = world.register_material(Material::HenyeyGreenstein { albedo: Texture::SolidColor(Color::new(0.9, 0.9, 0.9)), g: 0.8 });
+    let fog_boundary = Hittable::new_box(Point3::new(-20.0, 0.0, -20.0), Point3::new(20.0, 10.0, 20.0), white);
+    world.hittables.push(Hittable::new_constant_medium(fog_boundary, 0.05, fog_phase));
+
+    world.lights.push(Light::Point { position: Point3::new(-4.0, 3.0, 3.0), intensity: Color::new(30.0, 25.0, 18.0) });
+    world.lights.push(Light::new_spot(Point3::new(-4.0, 5.0, -3.0), Point3::new(0.0, 0.0, -1.0), Color::new(80.0, 80.0, 100.0), 25.0, 15.0));
+
+    world
+}
+
+fn cloud_scene() -> World {
+    let mut world = World {
+        materials: Vec::new(),
+        hittables: Vec::new(),
+        lights: Vec::new()
+    };
+
+    let ground = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.4, 0.45, 0.35)) });
+    world.hittables.push(Hittable::XZRect { mat_handle: ground, x0: -50.0, x1: 50.0, z0: -50.0, z1: 50.0, k: 0.0 });
+
+    // Cloud baked into a voxel grid: turbulence eroding a soft sphere
+    let resolution = 64;
+    let noise = Perlin::new();
+    let mut data = Vec::with_capacity(resolution * resolution * resolution);
+    for k in 0..resolution {
+        for j in 0..resolution {
+            for i in 0..resolution {
+                let p = Point3::new(i as f64 + 0.5, j as f64 + 0.5, k as f64 + 0.5) / resolution as f64 * 2.0 - Vector3::new(1.0, 1.0, 1.0);
+                let falloff = 1.0 - p.length() - 1.2 * noise.turb(&(4.0 * p), 5);
+                data.push(clamp(3.0 * falloff, 0.0, 1.0) as f32);
+            }
+        }
+    }
+
+    let cloud_min = Point3::new(-3.0, 1.0, -3.0);
+    let cloud_max = Point3::new(3.0, 7.0, 3.0);
+    let cloud_density = DensityField::new_grid(resolution, resolution, resolution, data, AABB::new(cloud_min, cloud_max));
+    let cloud = world.register_material(Material::MediumInterface {
+        base: None,
+        interior: Medium::new_heterogeneous(cloud_density, 2.0, Color::new(0.95, 0.95, 0.95), 0.6)
+    });
+    world.hittables.push(Hittable::new_box(cloud_min, cloud_max, cloud));
+
+    // Smoke plume driven directly by procedural turbulence
+    let smoke = world.register_material(Material::MediumInterface {
+        base: None,
+        interior: Medium::new_heterogeneous(DensityField::new_turbulence(2.0), 1.5, Color::new(0.3, 0.3, 0.3), 0.0)
+    });
+    world.hittables.push(Hittable::Sphere { mat_handle: smoke, center: Point3::new(0.0, 1.5, 6.0), radius: 1.5 });
+
+    world.lights.push(Light::new_directional(Vector3::new(-1.0, -1.0, -0.5), Color::new(3.0, 2.9, 2.7)));
+
+    world
+}
+
+fn volume_files_scene() -> World {
+    let mut world = World {
+        materials: Vec::new(),
+        hittables: Vec::new(),
+        lights: Vec::new()
+    };
+
+    let ground = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.5, 0.5, 0.5)) });
+    world.hittables.push(Hittable::XZRect { mat_handle: ground, x0: -50.0, x1: 50.0, z0: -50.0, z1: 50.0, k: 0.0 });
+
+    // Dense grid of a smoke column
+    // Media standing on the ground reach just below it, so that their boundaries don't coincide with it
+    let smoke_min = Point3::new(-1.5, -0.01, -4.5);
+    let smoke_max = Point3::new(1.5, 6.0, -1.5);
+    let smoke_density = DensityField::load("volumes/smoke.vol", AABB::new(smoke_min, smoke_max));
+    let smoke = world.register_material(Material::MediumInterface {
+        base: None,
+        interior: Medium::new_heterogeneous(smoke_density, 8.0, Color::new(0.6, 0.6, 0.6), 0.0)
+    });
+    world.hittables.push(Hittable::new_box(smoke_min, smoke_max, smoke));
+
+    // Sparse grid of a torus
+    let torus_min = Point3::new(-2.0, -0.01, 1.0);
+    let torus_max = Point3::new(2.0, 4.0, 5.0);
+    let torus_density = DensityField::load("volumes/torus.svol", AABB::new(torus_min, torus_max));
+    let torus = world.register_material(Material::MediumInterface {
+        base: None,
+        interior: Medium::new_heterogeneous(torus_density, 6.0, Color::new(0.9, 0.5, 0.3), 0.3)
+    });
+    world.hittables.push(Hittable::new_box(torus_min, torus_max, torus));
+
+    world.lights.push(Light::new_directional(Vector3::new(-1.0, -1.5, 0.5), Color::new(3.0, 2.9, 2.7)));
+
+    world
+}
+
+fn nested_media_scene() -> World {
+    let mut world = World {
+        materials: Vec::new(),
+        hittables: Vec::new(),
+        lights: Vec::new()
+    };
+
+    let ground = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.5, 0.5, 0.5)) });
+    world.hittables.push(Hittable::XZRect { mat_handle: ground, x0: -20.0, x1: 20.0, z0: -20.0, z1: 20.0, k: 0.0 });
+
+    // Haze filling the room the camera stands in
+    let haze = world.register_material(Material::MediumInterface {
+        base: None,
+        interior: Medium::new_homogeneous(Color::new(0.04, 0.04, 0.04), Color::new(0.9, 0.9, 0.9))
+    });
+    world.hittables.push(Hittable::new_box(Point3::new(-20.0, -1.0, -20.0), Point3::new(20.0, 10.0, 20.0), haze));
+
+    // Glass sphere filled with red smoke, and a clear glass sphere inside the haze
+    let glass = world.register_material(Material::Dielectric { ir: 1.5, absorption: Color::new(0.0, 0.0, 0.0), tint: Texture::Scalar(1.0) });
+    let smoke_filled = world.register_material(Material::MediumInterface {
+        base: Some(glass),
+        interior: Medium::new_homogeneous(Color::new(1.5, 1.5, 1.5), Color::new(0.9, 0.3, 0.2))
+    });
+    world.hittables.push(Hittable::Sphere { mat_handle: smoke_filled, center: Point3::new(0.0, 1.0, -2.5), radius: 1.0 });
+    world.hittables.push(Hittable::Sphere { mat_handle: glass, center: Point3::new(0.0, 1.0, 2.5), radius: 1.0 });
+
+    // Hollow shell of smoke, whose inner sphere faces inwards to make a single non-convex boundary
+    let shell = world.register_material(Material::MediumInterface {
+        base: None,
+        interior: Medium::new_homogeneous(Color::new(3.0, 3.0, 3.0), Color::new(0.8, 0.8, 0.8))
+    });
+    world.hittables.push(Hittable::Sphere { mat_handle: shell, center: Point3::new(0.0, 1.2, 0.0), radius: 1.2 });
+    world.hittables.push(Hittable::new_flip_face(Hittable::Sphere { mat_handle: shell, center: Point3::new(0.0, 1.2, 0.0), radius: 0.9 }));
+
+    world.lights.push(Light::new_spot(Point3::new(-3.0, 8.0, 0.0), Point3::new(0.0, 0.0, 0.0), Color::new(400.0, 380.0, 340.0), 40.0, 30.0));
+
+    world
+}
+
+fn fire_scene() -> World {
+    let mut world = World {
+        materials: Vec::new(),
+        hittables: Vec::new(),
+        lights: Vec::new()
+    };
+
+    let ground = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.5, 0.5, 0.5)) });
+    world.hittables.push(Hittable::XZRect { mat_handle: ground, x0: -20.0, x1: 20.0, z0: -20.0, z1: 20.0, k: 0.0 });
+
+    let white = world.register_material(Material::Lambertian { albedo: Texture::SolidColor(Color::new(0.73, 0.73, 0.73)) });
+    world.hittables.push(Hittable::Sphere { mat_handle: white, center: Point3::new(0.0, 0.7, -2.5), radius: 0.7 });
+
+    // Flame baked into density and temperature grids: a tapering column broken up by turbulence, hottest at its base
+    let (nx, ny, nz) = (32, 64, 32);
+    let noise = Perlin::new();
+    let mut density = Vec::with_capacity(nx * ny * nz);
+    let mut temperature = Vec::with_capacity(nx * ny * nz);
+    for k in 0..nz {
+        for j in 0..ny {
+            for i in 0..nx {
+                let p = Point3::new((i as f64 + 0.5) / nx as f64 - 0.5, (j as f64 + 0.5) / ny as f64, (k as f64 + 0.5) / nz as f64 - 0.5);
+                let width = 0.5 * (1.0 - p.y).powf(0.7);
+                let r = (p.x * p.x + p.z * p.z).sqrt() / width.max(1e-3);
+                let flame = clamp(1.2 - r - 0.5 * noise.turb(&(Vector3::new(6.0 * p.x, 3.0 * p.y, 6.0 * p.z)), 5), 0.0, 1.0);
+
+                density.push(flame as f32);
+                temperature.push((flame * (1.0 - 0.6 * p.y)) as f32);
+            }
+        }
+    }
+
+    // Reaching just below the ground, so that the boundary doesn't coincide with it
+    let fire_min = Point3::new(-1.0, -0.01, -1.0);
+    let fire_max = Point3::new(1.0, 4.0, 1.0);
+    let fire_bounds = AABB::new(fire_min, fire_max);
+    let fire = world.register_material(Material::MediumInterface {
+        base: None,
+        interior: Medium::new_heterogeneous(DensityField::new_grid(nx, ny, nz, density, fire_bounds), 6.0, Color::new(0.1, 0.1, 0.1), 0.0)
+            .with_emission(VolumeEmission::new_temperature(DensityField::new_grid(nx, ny, nz, temperature, fire_bounds), 1900.0, 20.0))
+    });
+    world.hittables.push(Hittable::new_box(fire_min, fire_max, fire));
+
+    // Glowing gas filling a sphere
+    let gas = world.register_material(Material::VolumeLight {
+        albedo: Texture::SolidColor(Color::new(0.5, 0.5, 0.5)),
+        emission: VolumeEmission::Texture(Texture::SolidColor(Color::new(0.3, 1.5, 3.0)))
+    });
+    let gas_boundary = Hittable::Sphere { mat_handle: gas, center: Point3::new(0.0, 1.0, 3.0), radius: 1.0 };
+    world.hittables.push(Hittable::new_constant_medium(gas_boundary, 0.8, gas));
+
+    world
+}
+
+fn texture_filtering_scene() -> World {
+    let mut world = World {
+        materials: Vec::new(),
+        hittables: Vec::new(),
+        lights: Vec::new()
+    };
+
+    // Map stretched over a floor running off to the horizon, where each pixel covers many texels. Wide footprints
+    // at its near and far edges are mirrored rather than picking up the opposite pole
+    let floor_texture = Texture::load_image("textures/earthmap.jpg").with_filter(Filter::Ewa).with_wrap(Wrap::Repeat, Wrap::Mirror);
+    let floor = world.register_material(Material::Lambertian { albedo: floor_texture });
+    world.hittables.push(Hittable::XZRect { mat_handle: floor, x0: -10.0, x1: 10.0, z0: -200.0, z1: 0.0, k: 0.0 });
+
+    // Distant globes small enough for mip mapping to matter, left as trilinear and right unfiltered
+    let trilinear = world.register_material(Material::Lambertian { albedo: Texture::load_image("textures/earthmap.jpg").with_filter(Filter::Trilinear).with_wrap(Wrap::Repeat, Wrap::Clamp) });
+    world.hittables.push(Hittable::Sphere { mat_handle: trilinear, center: Point3::new(-3.0, 2.0, -60.0), radius: 2.0 });
+    let nearest = world.register_material(Material::Lambertian { albedo: Texture::load_image("textures/earthmap.jpg") });
+    world.hittables.push(Hittable::Sphere { mat_handle: nearest, center: Point3::new(3.0, 2.0, -60.0), radius: 2.0 });
+
+    world
+}
+
+struct PixelChunk {
+    pub x: usize,
+    pub y: usize
+}
+
+struct Scene {
+    pub aspect_ratio: f64,
+    pub image_width: usize,
+    pub samples_per_pixel: usize,
+    pub background: Background,
+    pub look_from: Point3,
+    pub look_at: Point3,
+    pub vfov: f64,
+    // Trace sampled wavelengths instead of RGB
+    pub spectral: bool,
+    pub world: std::sync::Arc<World>
+}
+
+fn main() {
+    // Image
+    let thread_count = 10; // Find maximum thread count for CPU
+    let max_depth = 50;
+    let rr_min_depth = 5; // Bounces before russian roulette may terminate a path
+    let vup = Vector3::new(0.0, 1.0, 0.0);
+    let dist_to_focus = 10.0; 
+
+    let scene = match 7 {
+
+        0 => {
+            let world = Arc::new(random_scene());
+
+            // Camera
+            let look_from = Point3::new(13.0, 2.0, 3.0);
+            let look_at = Point3::new(0.0, 0.0, 0.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 100,
+                background: Background::Sky(Box::new(Sky::new(35.0, 60.0, 3.0, 0.5))),
+                look_from,
+                look_at,
+                vfov: 20.0,
+                spectral: false,
+                world
+            }
+        },
+        1 => {
+            let world = Arc::new(two_spheres_scene());
+
+            // Camera
+            let look_from = Point3::new(13.0, 2.0, 3.0);
+            let look_at = Point3::new(0.0, 0.0, 0.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 100,
+                background: Background::Color(Color::new(0.7, 0.8, 1.0)),
+                look_from,
+                look_at,
+                vfov: 20.0,
+                spectral: false,
+                world
+            }
+        },
+        2 => {
+            let world = Arc::new(two_perlin_spheres_scene());
+
+            // Camera
+            let look_from = Point3::new(13.0, 2.0, 3.0);
+            let look_at = Point3::new(0.0, 0.0, 0.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 100,
+                background: Background::Color(Color::new(0.7, 0.8, 1.0)),
+                look_from,
+                look_at,
+                vfov: 20.0,
+                spectral: false,
+                world
+            }
+        },
+        3 => {
+            let world = Arc::new(earth_scene());
+
+            // Camera
+            let look_from = Point3::new(13.0, 2.0, 3.0);
+            let look_at = Point3::new(0.0, 0.0, 0.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 100,
+                background: Background::Color(Color::new(0.7, 0.8, 1.0)),
+                look_from,
+                look_at,
+                vfov: 20.0,
+                spectral: false,
+                world
+            }
+        },
+        4 => {
+            let world = Arc::new(simple_light_scene());
+
+            // Camera
+            let look_from = Point3::new(26.0, 3.0, 6.0);
+            let look_at = Point3::new(0.0, 2.0, 0.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 100,
+                background: Background::Color(Color::new(0.0, 0.0, 0.0)),
+                look_from,
+                look_at,
+                vfov: 20.0,
+                spectral: false,
+                world
+            }
+        },
+        5 => {
+            let world = Arc::new(cornell_box_scene());
+
+            // Camera
+            let look_from = Point3::new(278.0, 278.0, -800.0);
+            let look_at = Point3::new(278.0, 278.0, 0.0);
+
+            Scene {
+                aspect_ratio: 1.0,
+                image_width: 600,
+                samples_per_pixel: 200,
+                background: Background::Color(Color::new(0.0, 0.0, 0.0)),
+                look_from,
+                look_at,
+                vfov: 40.0,
+                spectral: false,
+                world
+            }
+        },
+        6 => {
+            let world = Arc::new(cornell_box_smoke_scene());
+
+            // Camera
+            let look_from = Point3::new(278.0, 278.0, -800.0);
+            let look_at = Point3::new(278.0, 278.0, 0.0);
+
+            Scene {
+                aspect_ratio: 1.0,
+                image_width: 600,
+                samples_per_pixel: 40,
+                background: Background::Color(Color::new(0.0, 0.0, 0.0)),
+                look_from,
+                look_at,
+                vfov: 40.0,
+                spectral: false,
+                world
+            }
+        },
+        7 => {
+            let world = Arc::new(final_scene());
+
+            // Camera
+            let look_from = Point3::new(478.0, 278.0, -600.0);
+            let look_at = Point3::new(278.0, 278.0, 0.0);
+
+            Scene {
+                aspect_ratio: 1.0,
+                image_width: 800,
+                samples_per_pixel: 2000,
+                background: Background::Color(Color::new(0.0, 0.0, 0.0)),
+                look_from,
+                look_at,
+                vfov: 40.0,
+                spectral: false,
+                world
+            }
+        },
+        8 => {
+            let world = Arc::new(environment_scene());
+
+            // Camera
+            let look_from = Point3::new(13.0, 2.0, 3.0);
+            let look_at = Point3::new(0.0, 0.5, 0.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 100,
+                background: Background::Environment(Box::new(EnvironmentMap::load("textures/sky.hdr", 0.0, 1.0))),
+                look_from,
+                look_at,
+                vfov: 20.0,
+                spectral: false,
+                world
+            }
+        },
+        9 => {
+            let world = Arc::new(delta_lights_scene());
+
+            // Camera
+            let look_from = Point3::new(13.0, 2.0, 3.0);
+            let look_at = Point3::new(0.0, 0.5, 0.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 100,
+                background: Background::Color(Color::new(0.0, 0.0, 0.0)),
+                look_from,
+                look_at,
+                vfov: 20.0,
+                spectral: false,
+                world
+            }
+        },
+        10 => {
+            let world = Arc::new(conductors_scene());
+
+            // Camera
+            let look_from = Point3::new(16.0, 6.0, 0.0);
+            let look_at = Point3::new(0.0, 0.5, 0.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 100,
+                background: Background::Sky(Box::new(Sky::new(35.0, 60.0, 3.0, 0.5))),
+                look_from,
+                look_at,
+                vfov: 40.0,
+                spectral: false,
+                world
+            }
+        },
+        11 => {
+            let world = Arc::new(rough_dielectrics_scene());
+
+            // Camera
+            let look_from = Point3::new(13.0, 3.0, 0.0);
+            let look_at = Point3::new(0.0, 0.8, 0.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 100,
+                background: Background::Sky(Box::new(Sky::new(35.0, 60.0, 3.0, 0.5))),
+                look_from,
+                look_at,
+                vfov: 40.0,
+                spectral: false,
+                world
+            }
+        },
+        12 => {
+            let world = Arc::new(principled_scene());
+
+            // Camera
+            let look_from = Point3::new(13.0, 3.0, 0.0);
+            let look_at = Point3::new(0.0, 0.8, 0.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 100,
+                background: Background::Sky(Box::new(Sky::new(35.0, 60.0, 3.0, 0.5))),
+                look_from,
+                look_at,
+                vfov: 50.0,
+                spectral: false,
+                world
+            }
+        },
+        13 => {
+            let world = Arc::new(dispersion_scene());
+
+            // Camera
+            let look_from = Point3::new(8.0, 5.0, 0.0);
+            let look_at = Point3::new(0.0, 0.5, 0.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 500,
+                background: Background::Color(Color::new(0.0, 0.0, 0.0)),
+                look_from,
+                look_at,
+                vfov: 40.0,
+                spectral: true,
+                world
+            }
+        },
+        14 => {
+            let world = Arc::new(spectral_lights_scene());
+
+            // Camera
+            let look_from = Point3::new(12.0, 4.0, 0.0);
+            let look_at = Point3::new(0.0, 1.0, 0.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 200,
+                background: Background::Color(Color::new(0.0, 0.0, 0.0)),
+                look_from,
+                look_at,
+                vfov: 40.0,
+                spectral: true,
+                world
+            }
+        },
+
+        15 => {
+            let world = Arc::new(blackbody_scene());
+
+            // Camera
+            let look_from = Point3::new(12.0, 3.0, 0.0);
+            let look_at = Point3::new(0.0, 1.0, 0.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 200,
+                background: Background::Color(Color::new(0.0, 0.0, 0.0)),
+                look_from,
+                look_at,
+                vfov: 40.0,
+                spectral: true,
+                world
+            }
+        },
+
+        16 => {
+            let world = Arc::new(light_visibility_scene());
+
+            // Camera
+            let look_from = Point3::new(12.0, 3.0, 0.0);
+            let look_at = Point3::new(0.0, 1.0, 0.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 200,
+                background: Background::Color(Color::new(0.02, 0.02, 0.03)),
+                look_from,
+                look_at,
+                vfov: 40.0,
+                spectral: false,
+                world
+            }
+        },
+
+        17 => {
+            let world = Arc::new(mix_materials_scene());
+
+            // Camera
+            let look_from = Point3::new(12.0, 3.0, 0.0);
+            let look_at = Point3::new(0.0, 1.0, 0.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 200,
+                background: Background::Environment(Box::new(EnvironmentMap::load("textures/sky.hdr", 0.0, 1.0))),
+                look_from,
+                look_at,
+                vfov: 30.0,
+                spectral: false,
+                world
+            }
+        },
+
+        18 => {
+            let world = Arc::new(normal_mapping_scene());
+
+            // Camera
+            let look_from = Point3::new(10.0, 4.0, 0.0);
+            let look_at = Point3::new(0.0, 0.5, 0.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 100,
+                background: Background::Color(Color::new(0.1, 0.12, 0.15)),
+                look_from,
+                look_at,
+                vfov: 35.0,
+                spectral: false,
+                world
+            }
+        },
+
+        19 => {
+            let world = Arc::new(alpha_mask_scene());
+
+            // Camera
+            let look_from = Point3::new(10.0, 3.0, 2.0);
+            let look_at = Point3::new(0.0, 1.0, -1.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 100,
+                background: Background::Color(Color::new(0.5, 0.6, 0.8)),
+                look_from,
+                look_at,
+                vfov: 40.0,
+                spectral: false,
+                world
+            }
+        },
+
+        20 => {
+            let world = Arc::new(textured_parameters_scene());
+
+            // Camera
+            let look_from = Point3::new(12.0, 3.0, 0.0);
+            let look_at = Point3::new(0.0, 1.0, 0.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 100,
+                background: Background::Environment(Box::new(EnvironmentMap::load("textures/sky.hdr", 0.0, 1.0))),
+                look_from,
+                look_at,
+                vfov: 35.0,
+                spectral: false,
+                world
+            }
+        },
+
+        21 => {
+            let world = Arc::new(subsurface_scene());
+
+            // Camera
+            let look_from = Point3::new(12.0, 3.0, 0.0);
+            let look_at = Point3::new(0.0, 1.0, 0.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 200,
+                background: Background::Color(Color::new(0.05, 0.05, 0.07)),
+                look_from,
+                look_at,
+                vfov: 35.0,
+                spectral: false,
+                world
+            }
+        },
+
+        22 => {
+            let world = Arc::new(fog_scene());
+
+            // Camera
+            let look_from = Point3::new(12.0, 2.0, 0.0);
+            let look_at = Point3::new(0.0, 2.0, 0.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 200,
+                background: Background::Color(Color::new(0.0, 0.0, 0.0)),
+                look_from,
+                look_at,
+                vfov: 40.0,
+                spectral: false,
+                world
+            }
+        },
+
+        23 => {
+            let world = Arc::new(cloud_scene());
+
+            // Camera
+            let look_from = Point3::new(20.0, 4.0, 6.0);
+            let look_at = Point3::new(0.0, 3.0, 2.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 200,
+                background: Background::Sky(Box::new(Sky::new(35.0, 60.0, 3.0, 0.5))),
+                look_from,
+                look_at,
+                vfov: 40.0,
+                spectral: false,
+                world
+            }
+        },
+
+        24 => {
+            let world = Arc::new(volume_files_scene());
+
+            // Camera
+            let look_from = Point3::new(16.0, 5.0, 0.0);
+            let look_at = Point3::new(0.0, 2.0, 0.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 200,
+                background: Background::Sky(Box::new(Sky::new(35.0, 60.0, 3.0, 0.5))),
+                look_from,
+                look_at,
+                vfov: 40.0,
+                spectral: false,
+                world
+            }
+        },
+
+        25 => {
+            let world = Arc::new(nested_media_scene());
+
+            // Camera
+            let look_from = Point3::new(12.0, 3.0, 0.0);
+            let look_at = Point3::new(0.0, 1.0, 0.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 200,
+                background: Background::Color(Color::new(0.0, 0.0, 0.0)),
+                look_from,
+                look_at,
+                vfov: 40.0,
+                spectral: false,
+                world
+            }
+        },
+
+        26 => {
+            let world = Arc::new(fire_scene());
+
+            // Camera
+            let look_from = Point3::new(12.0, 3.0, 0.0);
+            let look_at = Point3::new(0.0, 1.5, 0.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 200,
+                background: Background::Color(Color::new(0.0, 0.0, 0.0)),
+                look_from,
+                look_at,
+                vfov: 40.0,
+                spectral: false,
+                world
+            }
+        },
+
+        27 => {
+            let world = Arc::new(texture_filtering_scene());
+
+            // Camera
+            let look_from = Point3::new(0.0, 1.5, 2.0);
+            let look_at = Point3::new(0.0, 1.0, -20.0);
+
+            Scene {
+                aspect_ratio: 16.0 / 9.0,
+                image_width: 400,
+                samples_per_pixel: 16,
+                background: Background::Sky(Box::new(Sky::new(35.0, 60.0, 3.0, 0.5))),
+                look_from,
+                look_at,
+                vfov: 40.0,
+                spectral: false,
+                world
+            }
+        },
+
+        _ => {
+            panic!("Unsupported scene selected")
         }
     };
     
     let image_width = scene.image_width;
     let image_height = (scene.image_width as f64 * scene.aspect_ratio) as usize;
 
-    let camera = Arc::new(Camera::new(&scene.look_from, &scene.look_at, &vup, scene.vfov, scene.aspect_ratio, 0.1, dist_to_focus, 0.0, 1.0));
+    let camera = Arc::new(Camera::new(&scene.look_from, &scene.look_at, &vup, scene.vfov, scene.aspect_ratio, 0.1, dist_to_focus, 0.0, 1.0).with_image_height(image_height));
+    let camera_media = Arc::new(enclosing_media(&scene.world, &scene.look_from));
 
     // Render
     println!("P3\n{} {}\n255\n", image_width, image_height);
@@ -480,26 +1820,35 @@ fn main() {
     let pixels_to_process_count = image_width * image_height;
 
     eprintln!(
-        "Rendering {}x{} ({} pixels) image with {} samples per pixel and a max depth of {}, using {} threads", 
+        "Rendering {}x{} ({} pixels) image with {} samples per pixel, a max depth of {} and russian roulette after {} bounces, using {} threads", 
         image_width,
         image_height,
         image_width * image_height,
         scene.samples_per_pixel,
         max_depth,
+        rr_min_depth,
         thread_count
         );
 
+    if scene.spectral {
+        eprintln!("Using spectral rendering with hero wavelength sampling");
+    }
+
     use std::time::Instant;
     use std::sync::mpsc;
     
     let now = Instant::now();
 
+    let background = Arc::new(scene.background);
+
     for i in 0..thread_count {
+        let background = Arc::clone(&background);
         let pixel_colors = Arc::clone(&pixel_colors);
         let world = scene.world.clone();
         let camera = Arc::clone(&camera);
+        let camera_media = Arc::clone(&camera_media);
         let samples_per_pixel = scene.samples_per_pixel;
-        let background = scene.background;
+        let spectral = scene.spectral;
 
         let (tx, rx) = mpsc::channel();
         thread_receivers.push(rx);
@@ -519,7 +1868,13 @@ fn main() {
 
                         let r = camera.get_ray(u, v);
 
-                        pixel_color += ray_color(&r, &background, &world.hittables, max_depth, &world.materials);
+                        if spectral {
+                            let wavelengths = sample_wavelengths();
+                            let radiance = ray_color(&r, &background, &world, &camera_media, max_depth, rr_min_depth, Some(wavelengths));
+                            pixel_color += spectral_to_rgb(&radiance, &wavelengths);
+                        } else {
+                            pixel_color += ray_color(&r, &background, &world, &camera_media, max_depth, rr_min_depth, None);
+                        }
                     }
 
                     local_pixel_colors[x][y] = pixel_color;
@@ -2,30 +2,309 @@ use crate::math::*;
 use crate::ray::*;
 use crate::hittable::*;
 use crate::texture::*;
+use crate::onb::*;
+use crate::microfacet::*;
+use crate::principled::*;
+use crate::spectrum::*;
+use crate::medium::*;
 
 pub enum Material {
     Lambertian { albedo: Texture },
-    Metal { albedo: Color, fuzz: f64 },
-    Dielectric { ir: f64 },
-    DiffuseLight { emit: Texture },
-    Isotropic { albedo: Texture }
+    Metal { albedo: Texture, fuzz: Texture },
+    // tint colors light at every interaction with the surface, absorption attenuates it inside the volume. The same
+    // goes for the rough and dispersive variants
+    Dielectric { ir: f64, absorption: Color, tint: Texture },
+    RoughDielectric { ir: f64, roughness: Texture, absorption: Color, tint: Texture },
+    DispersiveDielectric { dispersion: Dispersion, absorption: Color, tint: Texture },
+    Conductor { eta: Color, k: Color, roughness_u: Texture, roughness_v: Texture },
+    Principled(Box<Principled>),
+    DiffuseLight { emit: Texture, two_sided: bool },
+    SpectralLight { spectrum: Spectrum, scale: f64, rgb: Color, strength: Texture, two_sided: bool },
+    Isotropic { albedo: Texture },
+    // Phase function for media with anisotropic scattering, g > 0 scattering forward as in fog
+    HenyeyGreenstein { albedo: Texture, g: f64 },
+    // Isotropic phase function of a volume that also emits light, such as fire or glowing gas
+    VolumeLight { albedo: Texture, emission: VolumeEmission },
+    // Smooth dielectric boundary around a scattering interior, for wax, skin or marble. albedo is the single
+    // scattering albedo, read where the path enters
+    Subsurface { albedo: Texture, mean_free_path: Color, ir: f64 },
+    // Picks first or second at each hit, choosing second with probability given by amount's scalar value
+    Mix { first: MaterialHandle, second: MaterialHandle, amount: Texture },
+    // Smooth dielectric clear coat over any base material, with the coat's color applied to light passing through it
+    Coated { base: MaterialHandle, ir: f64, tint: Color },
+    // Tangent space normal map (RGB in [0,1] mapped to [-1,1]) over base, with strength scaling the tangent offsets
+    NormalMap { base: MaterialHandle, map: Texture, strength: f64 },
+    // Bumps base as if the surface was displaced along its normal by scale times the map's scalar value
+    BumpMap { base: MaterialHandle, map: Texture, scale: f64 },
+    // Cut-out mask over base: rays pass through where the mask's opacity is zero, and stochastically in between
+    AlphaMask { base: MaterialHandle, mask: Texture },
+    // Boundary of a medium filling the object, entered through its front faces. The surface itself scatters like
+    // base, or lets light through untouched without one, for media without a visible boundary such as fog
+    MediumInterface { base: Option<MaterialHandle>, interior: Medium }
+}
+
+// Complex index of refraction presets, sampled at roughly 650, 550 and 450 nm
+#[derive(Copy, Clone)]
+pub enum ConductorPreset {
+    Gold,
+    Copper,
+    Silver,
+    Aluminum
+}
+
+impl ConductorPreset {
+    pub fn eta_k(&self) -> (Color, Color) {
+        match self {
+            ConductorPreset::Gold => (Color::new(0.143, 0.374, 1.442), Color::new(3.983, 2.386, 1.603)),
+            ConductorPreset::Copper => (Color::new(0.200, 0.924, 1.102), Color::new(3.912, 2.452, 2.142)),
+            ConductorPreset::Silver => (Color::new(0.155, 0.117, 0.138), Color::new(4.828, 3.122, 2.147)),
+            ConductorPreset::Aluminum => (Color::new(1.657, 0.880, 0.521), Color::new(9.224, 6.270, 4.837))
+        }
+    }
+}
+
+pub struct ScatterRecord {
+    pub ray: Ray,
+    // BSDF times cosine for sampled lobes, or the full path weight for specular ones
+    pub attenuation: Color,
+    // Solid angle pdf of ray.direction, 1.0 for specular lobes
+    pub pdf: f64,
+    pub is_specular: bool
 }
 
 impl Material {
-    pub fn scatter(&self, ray: &Ray, rec: &HitRecord) -> Option<(Ray, Color)> {
+    // Roughness is perceptual roughness in [0,1] along the two tangent directions
+    pub fn new_conductor(preset: ConductorPreset, roughness_u: f64, roughness_v: f64) -> Material {
+        let (eta, k) = preset.eta_k();
+        Material::Conductor { eta, k, roughness_u: Texture::Scalar(roughness_u), roughness_v: Texture::Scalar(roughness_v) }
+    }
+
+    // Glass whose color is given as the fraction of light left after travelling the given distance inside it
+    pub fn new_tinted_dielectric(ir: f64, transmittance: Color, distance: f64) -> Material {
+        let absorption = Color::new(
+            -transmittance.x.ln() / distance,
+            -transmittance.y.ln() / distance,
+            -transmittance.z.ln() / distance
+        );
+
+        Material::Dielectric { ir, absorption, tint: Texture::Scalar(1.0) }
+    }
+
+    // Emitter defined by a measured spectrum, scaled so its luminance matches the given value. One-sided
+    // emitters only emit from the side the surface normal points to
+    pub fn new_spectral_light(spectrum: Spectrum, luminance: f64, two_sided: bool) -> Material {
+        let xyz = spectrum.to_xyz();
+        let scale = luminance / xyz.y;
+
+        Material::SpectralLight { rgb: xyz_to_rgb(&xyz) * scale, spectrum, scale, strength: Texture::Scalar(1.0), two_sided }
+    }
+
+    // Blackbody emitter at the given temperature in Kelvin, e.g. 2700 for a warm incandescent bulb
+    pub fn new_blackbody_light(temperature: f64, luminance: f64, two_sided: bool) -> Material {
+        Self::new_spectral_light(Spectrum::blackbody(temperature), luminance, two_sided)
+    }
+
+    // Blackbody emitter given its total emitted luminous power in lumens, spread over the area of its shape
+    pub fn new_blackbody_light_with_power(temperature: f64, lumens: f64, area: f64, two_sided: bool) -> Material {
+        // A Lambertian emitter with luminance L emits PI * L * area lumens from each emitting side
+        let sides = if two_sided { 2.0 } else { 1.0 };
+        Self::new_blackbody_light(temperature, lumens / (PI * area * sides), two_sided)
+    }
+
+    // Modulates the output of a spectral emitter by a scalar texture
+    pub fn with_strength(self, strength: Texture) -> Material {
+        match self {
+            Material::SpectralLight { spectrum, scale, rgb, strength: _, two_sided } => {
+                Material::SpectralLight { spectrum, scale, rgb, strength, two_sided }
+            },
+            material => material
+        }
+    }
+
+    // Blend of two registered materials by a constant factor, where 0 is all first and 1 all second
+    pub fn new_mix(first: MaterialHandle, second: MaterialHandle, amount: f64) -> Material {
+        Material::Mix { first, second, amount: Texture::Scalar(amount) }
+    }
+
+    // Medium filling the object, entered by rays transmitted through its front face
+    pub fn interior_medium(&self, rec: &HitRecord) -> Option<Medium> {
+        match self {
+            Material::Subsurface { albedo, mean_free_path, ir: _ } => {
+                Some(Medium::new_subsurface(albedo.get_hit_color_value(rec), *mean_free_path))
+            },
+            Material::MediumInterface { base: _, interior } => Some(interior.clone()),
+            _ => self.absorption().map(|absorption| Medium::Absorbing { sigma_a: absorption })
+        }
+    }
+
+    // Absorption coefficient inside tinted glass, which the path integrates over every segment it spends inside
+    pub fn absorption(&self) -> Option<Color> {
+        let absorption = match self {
+            Material::Dielectric { ir: _, absorption, tint: _ } => absorption,
+            Material::RoughDielectric { ir: _, roughness: _, absorption, tint: _ } => absorption,
+            Material::DispersiveDielectric { dispersion: _, absorption, tint: _ } => absorption,
+            _ => { return None; }
+        };
+
+        if absorption.x > 0.0 || absorption.y > 0.0 || absorption.z > 0.0 { Some(*absorption) } else { None }
+    }
+
+    // Surfaces that only delimit a medium, which rays cross without interacting
+    pub fn is_invisible(&self) -> bool {
+        matches!(self, Material::MediumInterface { base: None, interior: _ })
+    }
+
+    // Phase functions scatter inside media bounded by hittables, whose hits aren't on a surface
+    pub fn is_phase_function(&self) -> bool {
+        matches!(self, Material::Isotropic { albedo: _ } | Material::HenyeyGreenstein { albedo: _, g: _ } | Material::VolumeLight { albedo: _, emission: _ })
+    }
+
+    pub fn is_dispersive(&self) -> bool {
+        matches!(self, Material::DispersiveDielectric { dispersion: _, absorption: _, tint: _ })
+    }
+
+    // Whether eval can be nonzero, which is when sampling lights pays off. A coat runs it even on hits where it
+    // reflects specularly, since eval and pdf already account for the chance of reaching the base.
+    pub fn has_non_delta_lobe(&self, rec: &HitRecord, materials: &[Material]) -> bool {
+        match self {
+            Material::Lambertian { albedo: _ } | Material::Principled(_) => true,
+            Material::Isotropic { albedo: _ } | Material::VolumeLight { albedo: _, emission: _ } | Material::HenyeyGreenstein { albedo: _, g: _ } => true,
+            Material::RoughDielectric { ir: _, roughness, absorption: _, tint: _ } => {
+                !Self::dielectric_distribution(roughness, rec).effectively_smooth()
+            },
+            Material::Conductor { eta: _, k: _, roughness_u, roughness_v } => {
+                !Self::conductor_distribution(roughness_u, roughness_v, rec).effectively_smooth()
+            },
+            Material::Coated { base: _, ir: _, tint: _ } => {
+                let (base, base_rec) = Self::coat_base(rec, materials);
+                base.has_non_delta_lobe(&base_rec, materials)
+            },
+            _ => false
+        }
+    }
+
+    pub fn sample(&self, ray: &Ray, rec: &HitRecord, materials: &[Material]) -> Option<ScatterRecord> {
+        match self {
+            Material::Lambertian { albedo } => Self::lambertian_sample(albedo, ray, rec),
+            Material::Metal { albedo, fuzz } => Self::metal_sample(albedo, fuzz, ray, rec),
+            Material::Dielectric { ir, absorption: _, tint } => Self::dielectric_sample(*ir, tint, ray, rec),
+            Material::DispersiveDielectric { dispersion, absorption: _, tint } => Self::dispersive_dielectric_sample(dispersion, tint, ray, rec),
+            Material::RoughDielectric { ir, roughness, absorption: _, tint } => Self::rough_dielectric_sample(*ir, roughness, tint, ray, rec),
+            Material::Conductor { eta, k, roughness_u, roughness_v } => Self::conductor_sample(eta, k, roughness_u, roughness_v, ray, rec),
+            Material::Principled(principled) => principled.sample(ray, rec),
+            Material::DiffuseLight { emit: _, two_sided: _ } => None,
+            Material::SpectralLight { spectrum: _, scale: _, rgb: _, strength: _, two_sided: _ } => None,
+            Material::Isotropic { albedo } =>  Self::isotropic_sample(albedo, ray, rec),
+            Material::VolumeLight { albedo, emission: _ } => Self::isotropic_sample(albedo, ray, rec),
+            Material::HenyeyGreenstein { albedo, g } => Self::henyey_greenstein_sample(albedo, *g, ray, rec),
+            Material::Subsurface { albedo: _, mean_free_path: _, ir } => Self::dielectric_sample(*ir, &Texture::Scalar(1.0), ray, rec),
+            Material::Coated { base: _, ir, tint } => Self::coated_sample(*ir, tint, ray, rec, materials),
+            // Mixes and normal perturbations are resolved by resolve_material before scattering
+            Material::Mix { first: _, second: _, amount: _ } => None,
+            Material::NormalMap { base: _, map: _, strength: _ } => None,
+            Material::BumpMap { base: _, map: _, scale: _ } => None,
+            Material::AlphaMask { base: _, mask: _ } => None,
+            Material::MediumInterface { base: _, interior: _ } => {
+                Some(ScatterRecord {
+                    ray: Ray::with_wavelength(rec.point, ray.direction, ray.time, ray.wavelength),
+                    attenuation: Color::new(1.0, 1.0, 1.0),
+                    pdf: 1.0,
+                    is_specular: true
+                })
+            }
+        }
+    }
+
+    // BSDF times cosine for the given scattered direction. Specular lobes evaluate to zero.
+    pub fn eval(&self, ray: &Ray, rec: &HitRecord, direction: &Vector3, materials: &[Material]) -> Color {
         match self {
-            Material::Lambertian { albedo } => Self::lambertian_scatter(albedo, ray, rec),
-            Material::Metal { albedo, fuzz } => Self::metal_scatter(albedo, *fuzz, ray, rec),
-            Material::Dielectric { ir } => Self::dielectric_scatter(*ir, ray, rec),
-            Material::DiffuseLight { emit: _ } => None,
-            Material::Isotropic { albedo } =>  Self::isotropic_scatter(albedo, ray, rec)
+            Material::Coated { base: _, ir, tint } => {
+                let (base, base_rec) = Self::coat_base(rec, materials);
+                if !rec.front_face {
+                    return base.eval(ray, &base_rec, direction, materials);
+                }
+
+                let (transmitted_o, transmitted_i) = Self::coat_transmission(*ir, ray, rec, direction);
+                *tint * transmitted_o * transmitted_i * base.eval(ray, &base_rec, direction, materials)
+            },
+            Material::RoughDielectric { ir, roughness, absorption: _, tint } => {
+                let (_, wo, wi) = Self::shading_frame(ray, rec, direction);
+                let value = rough_dielectric_eval(Self::relative_ior(*ir, rec), &Self::dielectric_distribution(roughness, rec), &wo, &wi);
+                tint.get_hit_color_value(rec) * value
+            },
+            Material::Conductor { eta, k, roughness_u, roughness_v } => {
+                let (_, wo, wi) = Self::shading_frame(ray, rec, direction);
+                Self::conductor_eval(eta, k, &Self::conductor_distribution(roughness_u, roughness_v, rec), &wo, &wi)
+            },
+            Material::Lambertian { albedo } => {
+                Self::lambertian_eval(albedo, rec, direction)
+            },
+            Material::Principled(principled) => {
+                principled.eval(ray, rec, direction)
+            },
+            Material::Isotropic { albedo } | Material::VolumeLight { albedo, emission: _ } => {
+                albedo.get_hit_color_value(rec) * (1.0 / (4.0 * PI))
+            },
+            Material::HenyeyGreenstein { albedo, g } => {
+                let cos_theta = Vector3::dot(&Vector3::normalize(&ray.direction), &Vector3::normalize(direction));
+                albedo.get_hit_color_value(rec) * henyey_greenstein(cos_theta, *g)
+            },
+            _ => {
+                Color::new(0.0, 0.0, 0.0)
+            }
         }
     }
 
-    pub fn emitted(&self, u: f64, v: f64, p: &Point3) -> Color {
+    // Solid angle pdf with which sample() would produce the given direction
+    pub fn pdf(&self, ray: &Ray, rec: &HitRecord, direction: &Vector3, materials: &[Material]) -> f64 {
         match self {
-            Material::DiffuseLight { emit } => {
-                emit.get_color_value(u, v, p)
+            Material::Coated { base: _, ir, tint: _ } => {
+                let (base, base_rec) = Self::coat_base(rec, materials);
+                if !rec.front_face {
+                    return base.pdf(ray, &base_rec, direction, materials);
+                }
+
+                // The base is only sampled when the coat doesn't reflect
+                let (transmitted_o, _) = Self::coat_transmission(*ir, ray, rec, direction);
+                transmitted_o * base.pdf(ray, &base_rec, direction, materials)
+            },
+            Material::RoughDielectric { ir, roughness, absorption: _, tint: _ } => {
+                let (_, wo, wi) = Self::shading_frame(ray, rec, direction);
+                rough_dielectric_pdf(Self::relative_ior(*ir, rec), &Self::dielectric_distribution(roughness, rec), &wo, &wi)
+            },
+            Material::Conductor { eta: _, k: _, roughness_u, roughness_v } => {
+                let (_, wo, wi) = Self::shading_frame(ray, rec, direction);
+                Self::conductor_pdf(&Self::conductor_distribution(roughness_u, roughness_v, rec), &wo, &wi)
+            },
+            Material::Lambertian { albedo: _ } => {
+                Self::cosine_pdf(rec, direction)
+            },
+            Material::Principled(principled) => {
+                principled.pdf(ray, rec, direction)
+            },
+            Material::Isotropic { albedo: _ } | Material::VolumeLight { albedo: _, emission: _ } => {
+                1.0 / (4.0 * PI)
+            },
+            Material::HenyeyGreenstein { albedo: _, g } => {
+                let cos_theta = Vector3::dot(&Vector3::normalize(&ray.direction), &Vector3::normalize(direction));
+                henyey_greenstein(cos_theta, *g)
+            },
+            _ => {
+                0.0
+            }
+        }
+    }
+
+    pub fn emitted(&self, rec: &HitRecord) -> Color {
+        match self {
+            Material::DiffuseLight { emit, two_sided } if *two_sided || rec.front_face => {
+                emit.get_hit_color_value(rec)
+            },
+            Material::SpectralLight { spectrum: _, scale: _, rgb, strength, two_sided } if *two_sided || rec.front_face => {
+                *rgb * strength.get_hit_scalar_value(rec)
+            },
+            Material::VolumeLight { albedo: _, emission } => {
+                emission.value(&rec.point)
             },
             _ => {
                 Color::new(0.0, 0.0, 0.0)
@@ -33,34 +312,70 @@ impl Material {
         }
     }
 
-    fn lambertian_scatter(albedo: &Texture, ray: &Ray, rec: &HitRecord) -> Option<(Ray, Color)> {
-        let mut scatter_direction = rec.normal + Vector3::random_unit_vector();
-        // Catch degenerate scatter_direction
-        if scatter_direction.near_zero() {
-            scatter_direction = rec.normal;
+    // Emission at the three wavelengths carried by a spectral path
+    pub fn emitted_samples(&self, rec: &HitRecord, wavelengths: &Vector3) -> Color {
+        match self {
+            Material::SpectralLight { spectrum, scale, rgb: _, strength, two_sided } => {
+                if !*two_sided && !rec.front_face {
+                    return Color::new(0.0, 0.0, 0.0);
+                }
+
+                *scale * strength.get_hit_scalar_value(rec) * Color::new(spectrum.sample(wavelengths.x), spectrum.sample(wavelengths.y), spectrum.sample(wavelengths.z))
+            },
+            Material::VolumeLight { albedo: _, emission } => {
+                emission.value_samples(&rec.point, wavelengths)
+            },
+            _ => {
+                rgb_to_illuminant_samples(&self.emitted(rec), wavelengths)
+            }
         }
-        let scattered = Ray::with_time(rec.point, scatter_direction, ray.time);
+    }
 
+    fn lambertian_sample(albedo: &Texture, ray: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        let uvw = ONB::build_from_w(&rec.normal);
+        let direction = uvw.local(&Vector3::random_cosine_direction());
 
-        let attenuation = albedo.get_color_value(rec.u, rec.v, &rec.point);
-        
-        Some((scattered, attenuation))
+        let pdf = Self::cosine_pdf(rec, &direction);
+
+        // Catch directions grazing the surface
+        if pdf <= 0.0 {
+            return None;
+        }
+
+        Some(ScatterRecord {
+            ray: Ray::with_wavelength(rec.point, direction, ray.time, ray.wavelength),
+            attenuation: Self::lambertian_eval(albedo, rec, &direction),
+            pdf,
+            is_specular: false
+        })
     }
     
-    fn metal_scatter(albedo: &Color, fuzz: f64, ray: &Ray, rec: &HitRecord) -> Option<(Ray, Color)> {
+    fn lambertian_eval(albedo: &Texture, rec: &HitRecord, direction: &Vector3) -> Color {
+        let cosine = Vector3::dot(&rec.normal, &Vector3::normalize(direction)).max(0.0);
+        albedo.get_hit_color_value(rec) * (cosine / PI)
+    }
+
+    fn cosine_pdf(rec: &HitRecord, direction: &Vector3) -> f64 {
+        let cosine = Vector3::dot(&rec.normal, &Vector3::normalize(direction));
+        if cosine > 0.0 { cosine / PI } else { 0.0 }
+    }
+
+    fn metal_sample(albedo: &Texture, fuzz: &Texture, ray: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
         let reflected = Vector3::reflect(&Vector3::normalize(&ray.direction), &rec.normal);
-        let with_fuzz = reflected + fuzz * Vector3::random_in_unit_sphere();
-        let scattered = Ray::with_time(rec.point, with_fuzz, ray.time);
+        let with_fuzz = reflected + fuzz.get_hit_scalar_value(rec) * Vector3::random_in_unit_sphere();
+        let scattered = Ray::with_wavelength(rec.point, with_fuzz, ray.time, ray.wavelength);
         
         if Vector3::dot(&scattered.direction, &rec.normal) > 0.0 {
-            Some((scattered, *albedo))
+            Some(ScatterRecord { ray: scattered, attenuation: albedo.get_hit_color_value(rec), pdf: 1.0, is_specular: true })
         } else {
             None
         }
     }
 
-    fn dielectric_scatter(ir: f64, ray: &Ray, rec: &HitRecord) -> Option<(Ray, Color)> {
-        let attenuation = Color::new(1.0, 1.0, 1.0);
+    // Beer-Lambert attenuation for the segment a ray travelled inside the object before hitting its boundary
+    // from within
+    fn dielectric_sample(ir: f64, tint: &Texture, ray: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        let attenuation = tint.get_hit_color_value(rec);
         let refraction_ratio = if rec.front_face { 1.0 / ir } else { ir };
 
         let unit_direction = Vector3::normalize(&ray.direction);
@@ -76,14 +391,318 @@ impl Material {
             }
         };
         
-        let scattered = Ray::with_time(rec.point, direction, ray.time);
+        let scattered = Ray::with_wavelength(rec.point, direction, ray.time, ray.wavelength);
+
+        Some(ScatterRecord { ray: scattered, attenuation, pdf: 1.0, is_specular: true })
+    }
+
+    // Frame around the shading normal whose x and y axes follow the surface's u and v directions, so that
+    // anisotropic roughness stays aligned with the surface
+    fn surface_frame(rec: &HitRecord) -> ONB {
+        let (tangent, bitangent) = Self::tangent_frame(rec);
+        ONB { u: tangent, v: bitangent, w: rec.normal }
+    }
+
+    // Local frame around the shading normal, with the outgoing (towards the viewer) and incident directions in it
+    fn shading_frame(ray: &Ray, rec: &HitRecord, direction: &Vector3) -> (ONB, Vector3, Vector3) {
+        let frame = Self::surface_frame(rec);
+        let wo = frame.to_local(&(-Vector3::normalize(&ray.direction)));
+        let wi = frame.to_local(&Vector3::normalize(direction));
+        (frame, wo, wi)
+    }
+
+    fn conductor_distribution(roughness_u: &Texture, roughness_v: &Texture, rec: &HitRecord) -> TrowbridgeReitz {
+        let alpha_x = TrowbridgeReitz::roughness_to_alpha(roughness_u.get_hit_scalar_value(rec));
+        let alpha_y = TrowbridgeReitz::roughness_to_alpha(roughness_v.get_hit_scalar_value(rec));
+        TrowbridgeReitz::new(alpha_x, alpha_y)
+    }
+
+    fn conductor_sample(eta: &Color, k: &Color, roughness_u: &Texture, roughness_v: &Texture, ray: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        let frame = Self::surface_frame(rec);
+        let wo = frame.to_local(&(-Vector3::normalize(&ray.direction)));
+        if wo.z <= 0.0 {
+            return None;
+        }
+
+        let distribution = Self::conductor_distribution(roughness_u, roughness_v, rec);
+
+        if distribution.effectively_smooth() {
+            let wi = Vector3::new(-wo.x, -wo.y, wo.z);
+            return Some(ScatterRecord {
+                ray: Ray::with_wavelength(rec.point, frame.local(&wi), ray.time, ray.wavelength),
+                attenuation: fresnel_conductor_color(wo.z, eta, k),
+                pdf: 1.0,
+                is_specular: true
+            });
+        }
+
+        let wm = distribution.sample_wm(&wo);
+        let wi = reflect_local(&wo, &wm);
+        if wi.z <= 0.0 {
+            return None;
+        }
+
+        let pdf = Self::conductor_pdf(&distribution, &wo, &wi);
+        if pdf <= 0.0 {
+            return None;
+        }
+
+        Some(ScatterRecord {
+            ray: Ray::with_wavelength(rec.point, frame.local(&wi), ray.time, ray.wavelength),
+            attenuation: Self::conductor_eval(eta, k, &distribution, &wo, &wi),
+            pdf,
+            is_specular: false
+        })
+    }
+
+    fn conductor_eval(eta: &Color, k: &Color, distribution: &TrowbridgeReitz, wo: &Vector3, wi: &Vector3) -> Color {
+        if distribution.effectively_smooth() || wo.z <= 0.0 || wi.z <= 0.0 {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+
+        let wm = *wi + *wo;
+        if wm.near_zero() {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+        let wm = Vector3::normalize(&wm);
+
+        // D * G * F / (4 * cos_o * cos_i), times cos_i
+        let fresnel = fresnel_conductor_color(Vector3::dot(wo, &wm).abs(), eta, k);
+        fresnel * (distribution.d(&wm) * distribution.g(wo, wi) / (4.0 * wo.z))
+    }
+
+    fn conductor_pdf(distribution: &TrowbridgeReitz, wo: &Vector3, wi: &Vector3) -> f64 {
+        if distribution.effectively_smooth() || wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+
+        let wm = *wi + *wo;
+        if wm.near_zero() {
+            return 0.0;
+        }
+        let wm = Vector3::normalize(&wm);
+
+        distribution.d_visible(wo, &wm) / (4.0 * Vector3::dot(wo, &wm).abs())
+    }
+
+    fn dispersive_dielectric_sample(dispersion: &Dispersion, tint: &Texture, ray: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        // Paths that don't carry a wavelength yet pick one here and only transport that wavelength from now on
+        let (wavelength, weight) = match ray.wavelength {
+            Some(wavelength) => (wavelength, Color::new(1.0, 1.0, 1.0)),
+            None => {
+                let wavelength = sample_wavelength();
+                (wavelength, wavelength_rgb_weight(wavelength))
+            }
+        };
+
+        let mut srec = Self::dielectric_sample(dispersion.ior(wavelength), tint, ray, rec)?;
+        srec.ray.wavelength = Some(wavelength);
+        srec.attenuation = srec.attenuation * weight;
+
+        Some(srec)
+    }
+
+    // Index of refraction on the far side of the shading normal over the one on the ray's side
+    fn relative_ior(ir: f64, rec: &HitRecord) -> f64 {
+        if rec.front_face { ir } else { 1.0 / ir }
+    }
+
+    fn dielectric_distribution(roughness: &Texture, rec: &HitRecord) -> TrowbridgeReitz {
+        let alpha = TrowbridgeReitz::roughness_to_alpha(roughness.get_hit_scalar_value(rec));
+        TrowbridgeReitz::new(alpha, alpha)
+    }
+
+    fn rough_dielectric_sample(ir: f64, roughness: &Texture, tint: &Texture, ray: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        let frame = Self::surface_frame(rec);
+        let wo = frame.to_local(&(-Vector3::normalize(&ray.direction)));
+        if wo.z <= 0.0 {
+            return None;
+        }
+
+        let eta = Self::relative_ior(ir, rec);
+        let distribution = Self::dielectric_distribution(roughness, rec);
+
+        if distribution.effectively_smooth() {
+            let reflectance = fresnel_dielectric(wo.z, eta);
+            let (wi, attenuation) = if random_double() < reflectance {
+                (Vector3::new(-wo.x, -wo.y, wo.z), 1.0)
+            } else {
+                let (wi, etap) = refract_local(&wo, &Vector3::new(0.0, 0.0, 1.0), eta)?;
+                // Radiance is compressed into a smaller solid angle when entering a denser medium
+                (wi, 1.0 / (etap * etap))
+            };
+
+            return Some(ScatterRecord {
+                ray: Ray::with_wavelength(rec.point, frame.local(&wi), ray.time, ray.wavelength),
+                attenuation: attenuation * tint.get_hit_color_value(rec),
+                pdf: 1.0,
+                is_specular: true
+            });
+        }
+
+        let wm = distribution.sample_wm(&wo);
+        let reflectance = fresnel_dielectric(Vector3::dot(&wo, &wm), eta);
+        let wi = if random_double() < reflectance {
+            reflect_local(&wo, &wm)
+        } else {
+            refract_local(&wo, &wm, eta)?.0
+        };
+
+        let pdf = rough_dielectric_pdf(eta, &distribution, &wo, &wi);
+        if pdf <= 0.0 {
+            return None;
+        }
+
+        Some(ScatterRecord {
+            ray: Ray::with_wavelength(rec.point, frame.local(&wi), ray.time, ray.wavelength),
+            attenuation: tint.get_hit_color_value(rec) * rough_dielectric_eval(eta, &distribution, &wo, &wi),
+            pdf,
+            is_specular: false
+        })
+    }
+
+    fn isotropic_sample(albedo: &Texture, ray: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        let scattered = Ray::with_wavelength(rec.point, Vector3::random_unit_vector(), ray.time, ray.wavelength);
+        Some(ScatterRecord {
+            ray: scattered,
+            attenuation: albedo.get_hit_color_value(rec) * (1.0 / (4.0 * PI)),
+            pdf: 1.0 / (4.0 * PI),
+            is_specular: false
+        })
+    }
+
+    fn henyey_greenstein_sample(albedo: &Texture, g: f64, ray: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        let direction = sample_henyey_greenstein(&ray.direction, g);
+        let pdf = henyey_greenstein(Vector3::dot(&Vector3::normalize(&ray.direction), &direction), g);
+
+        Some(ScatterRecord {
+            ray: Ray::with_wavelength(rec.point, direction, ray.time, ray.wavelength),
+            attenuation: albedo.get_hit_color_value(rec) * pdf,
+            pdf,
+            is_specular: false
+        })
+    }
+
+    // Base of a coat as chosen by resolve_material, with the record it sees
+    fn coat_base<'a>(rec: &HitRecord, materials: &'a [Material]) -> (&'a Material, HitRecord) {
+        let (base, normal) = rec.coat_bases[rec.coat_level];
+        let mut base_rec = *rec;
+        base_rec.normal = normal;
+        base_rec.coat_level += 1;
+        (&materials[base.0 - 1], base_rec)
+    }
 
-        Some((scattered, attenuation))
+    fn coated_sample(ir: f64, tint: &Color, ray: &Ray, rec: &HitRecord, materials: &[Material]) -> Option<ScatterRecord> {
+        let (base, base_rec) = Self::coat_base(rec, materials);
+        if !rec.front_face {
+            return base.sample(ray, &base_rec, materials);
+        }
+
+        let unit_direction = Vector3::normalize(&ray.direction);
+        let reflected_o = fresnel_dielectric(Vector3::dot(&(-unit_direction), &rec.normal), ir);
+
+        if random_double() < reflected_o {
+            let reflected = Vector3::reflect(&unit_direction, &rec.normal);
+
+            return Some(ScatterRecord {
+                ray: Ray::with_wavelength(rec.point, reflected, ray.time, ray.wavelength),
+                attenuation: Color::new(1.0, 1.0, 1.0),
+                pdf: 1.0,
+                is_specular: true
+            });
+        }
+
+        let mut srec = base.sample(ray, &base_rec, materials)?;
+        let (transmitted_o, transmitted_i) = Self::coat_transmission(ir, ray, rec, &srec.ray.direction);
+
+        if srec.is_specular {
+            // The probability of entering the coat cancels its transmittance
+            srec.attenuation = *tint * transmitted_i * srec.attenuation;
+        } else {
+            srec.attenuation = *tint * transmitted_o * transmitted_i * srec.attenuation;
+            srec.pdf *= transmitted_o;
+        }
+
+        Some(srec)
     }
 
-    fn isotropic_scatter(albedo: &Texture, ray: &Ray, rec: &HitRecord) -> Option<(Ray, Color)> {
-        let scattered = Ray::with_time(rec.point, Vector3::random_in_unit_sphere(), ray.time);
-        Some((scattered, albedo.get_color_value(rec.u, rec.v, &rec.point)))
+    // Fraction of light transmitted through the coat along the incoming and scattered directions
+    fn coat_transmission(ir: f64, ray: &Ray, rec: &HitRecord, direction: &Vector3) -> (f64, f64) {
+        let cos_o = Vector3::dot(&(-Vector3::normalize(&ray.direction)), &rec.normal);
+        let cos_i = Vector3::dot(&Vector3::normalize(direction), &rec.normal).abs();
+
+        (1.0 - fresnel_dielectric(cos_o, ir), 1.0 - fresnel_dielectric(cos_i, ir))
+    }
+
+    // Orthonormal tangent and bitangent around the shading normal, following the u and v directions
+    fn tangent_frame(rec: &HitRecord) -> (Vector3, Vector3) {
+        let n = rec.normal;
+        let mut tangent = rec.dpdu - Vector3::dot(&n, &rec.dpdu) * n;
+        if tangent.near_zero() {
+            tangent = ONB::build_from_w(&n).u;
+        }
+        let tangent = Vector3::normalize(&tangent);
+
+        let bitangent = Vector3::cross(&n, &tangent);
+        let bitangent = if Vector3::dot(&bitangent, &rec.dpdv) < 0.0 { -bitangent } else { bitangent };
+
+        (tangent, bitangent)
+    }
+
+    fn apply_normal_map(map: &Texture, strength: f64, ray: &Ray, rec: &mut HitRecord) {
+        let (tangent, bitangent) = Self::tangent_frame(rec);
+        let texel = map.get_hit_color_value(rec);
+        let offset = 2.0 * texel - Color::new(1.0, 1.0, 1.0);
+
+        let normal = strength * offset.x * tangent + strength * offset.y * bitangent + offset.z.max(0.0) * rec.normal;
+        if !normal.near_zero() {
+            rec.normal = Self::face_viewer(ray, &rec.normal, &Vector3::normalize(&normal));
+        }
+    }
+
+    fn apply_bump_map(map: &Texture, scale: f64, ray: &Ray, rec: &mut HitRecord) {
+        // Finite difference steps, one texel for images
+        let (du, dv) = match map {
+            Texture::Image(w, h, _, _, _, _, _, _) => (1.0 / *w as f64, 1.0 / *h as f64),
+            _ => (0.0005, 0.0005)
+        };
+
+        let displace = map.get_scalar_value(rec.u, rec.v, &rec.point);
+        let displace_u = map.get_scalar_value(rec.u + du, rec.v, &(rec.point + du * rec.dpdu));
+        let displace_v = map.get_scalar_value(rec.u, rec.v + dv, &(rec.point + dv * rec.dpdv));
+
+        // Derivatives of the displaced surface p + scale * d(u, v) * n, ignoring the change of n itself
+        let dpdu = rec.dpdu + scale * (displace_u - displace) / du * rec.normal;
+        let dpdv = rec.dpdv + scale * (displace_v - displace) / dv * rec.normal;
+
+        let normal = Vector3::cross(&dpdu, &dpdv);
+        if normal.near_zero() {
+            return;
+        }
+
+        let normal = Vector3::normalize(&normal);
+        let normal = if Vector3::dot(&normal, &rec.normal) < 0.0 { -normal } else { normal };
+        rec.normal = Self::face_viewer(ray, &rec.normal, &normal);
+    }
+
+    // Perturbed normals may turn away from the viewer at grazing angles, where samplers would find the outgoing
+    // direction below the surface. Such normals are bent back towards the unperturbed one until the viewer is
+    // just in front of them
+    fn face_viewer(ray: &Ray, unperturbed: &Vector3, normal: &Vector3) -> Vector3 {
+        const MIN_COS: f64 = 0.01;
+
+        let wo = -Vector3::normalize(&ray.direction);
+        let cos_o = Vector3::dot(&wo, normal);
+        if cos_o >= MIN_COS {
+            return *normal;
+        }
+
+        let cos_unperturbed = Vector3::dot(&wo, unperturbed);
+        if cos_unperturbed <= MIN_COS {
+            return *unperturbed;
+        }
+
+        Vector3::normalize(&(*normal + (MIN_COS - cos_o) / (cos_unperturbed - MIN_COS) * *unperturbed))
     }
 
     fn reflectance(cosine: f64, ref_idx: f64) -> f64 {
@@ -97,4 +716,62 @@ impl Material {
 #[derive(Default, Copy, Clone)]
 pub struct MaterialHandle(pub usize); // Index into materials vec
 
+// Coats a hit can have on top of each other
+pub const MAX_COAT_LAYERS: usize = 4;
+
+// Looks up the material behind a handle, making the stochastic choice for any Mix materials at this hit and
+// applying normal and bump maps to the record's shading normal. The chosen material is kept in the record along with
+// the opacity of the alpha masks on the way to it, so the alpha test and shading agree on the choice
+pub fn resolve_material<'a>(materials: &'a [Material], mat_handle: MaterialHandle, ray: &Ray, rec: &mut HitRecord) -> &'a Material {
+    rec.opacity = 1.0;
+    let handle = resolve_handle(materials, mat_handle, ray, rec);
+    rec.resolved_handle = handle;
+    &materials[handle.0 - 1]
+}
+
+fn resolve_handle(materials: &[Material], mat_handle: MaterialHandle, ray: &Ray, rec: &mut HitRecord) -> MaterialHandle {
+    let mut handle = mat_handle;
+    loop {
+        handle = match &materials[handle.0 - 1] {
+            Material::Mix { first, second, amount } => {
+                if random_double() < amount.get_hit_scalar_value(rec) { *second } else { *first }
+            },
+            Material::NormalMap { base, map, strength } => {
+                Material::apply_normal_map(map, *strength, ray, rec);
+                *base
+            },
+            Material::BumpMap { base, map, scale } => {
+                Material::apply_bump_map(map, *scale, ray, rec);
+                *base
+            },
+            Material::AlphaMask { base, mask } => {
+                rec.opacity *= mask.get_opacity_value(rec.u, rec.v, &rec.point);
+                *base
+            },
+            Material::MediumInterface { base: Some(base), interior: _ } => {
+                *base
+            },
+            // Coats nested deeper than the record has room for are left out, leaving their base
+            Material::Coated { base, ir: _, tint: _ } if rec.coat_level >= MAX_COAT_LAYERS => {
+                *base
+            },
+            Material::Coated { base, ir: _, tint: _ } => {
+                // The coat keeps the geometric normal while the base may perturb its own
+                let mut base_rec = *rec;
+                base_rec.coat_level += 1;
+                let base = resolve_handle(materials, *base, ray, &mut base_rec);
+
+                rec.coat_bases = base_rec.coat_bases;
+                rec.coat_bases[rec.coat_level] = (base, base_rec.normal);
+                // Masks on the base cut out the coat along with it
+                rec.opacity = base_rec.opacity;
+                return handle;
+            },
+            _ => {
+                return handle;
+            }
+        };
+    }
+}
+
 
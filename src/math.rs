@@ -79,6 +79,19 @@ impl Vector3 {
         Self::normalize(&Self::random_in_unit_sphere())
     }
 
+    // Cosine-weighted direction around +Z, pdf = cos(theta) / PI
+    pub fn random_cosine_direction() -> Vector3 {
+        let r1 = random_double();
+        let r2 = random_double();
+        let z = (1.0 - r2).sqrt();
+
+        let phi = 2.0 * PI * r1;
+        let x = phi.cos() * r2.sqrt();
+        let y = phi.sin() * r2.sqrt();
+
+        Vector3::new(x, y, z)
+    }
+
     pub fn dot(u: &Vector3, v: &Vector3) -> f64 {
         u.x * v.x + u.y * v.y + u.z * v.z 
     }
@@ -285,6 +298,20 @@ pub fn clamp(x: f64, min: f64, max: f64) -> f64 {
     else { x }
 }
 
+// Relative luminance of a linear sRGB color
+pub fn luminance(color: &Color) -> f64 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
+// CIE XYZ to linear sRGB (D65 white point)
+pub fn xyz_to_rgb(xyz: &Vector3) -> Color {
+    Color::new(
+         3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+        -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+         0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z
+    )
+}
+
 pub fn sphere_uv(p: &Point3) -> (f64, f64) {
     // p: a given point on the sphere of radius one, centered at the origin.
     // u: returned value [0,1] of angle around the Y axis from X=-1.
@@ -0,0 +1,590 @@
+use crate::math::*;
+use crate::ray::*;
+use crate::spectrum::*;
+use crate::onb::*;
+use crate::aabb::*;
+use crate::perlin::*;
+use crate::material::{Material, MaterialHandle};
+use crate::texture::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Participating medium filling the interior of an object, traversed by free-flight sampling
+#[derive(Clone)]
+pub enum Medium {
+    // Uniform density of scatterers, with extinction coefficient sigma_t and single scattering albedo per channel.
+    // Emitting volumes glow at every collision
+    Homogeneous { sigma_t: Color, albedo: Color, emission: Option<Arc<VolumeEmission>> },
+    // Density of scale times the field's value, bounded by majorant, with the same extinction in every channel.
+    // Scattering follows a Henyey-Greenstein phase function, and emitting volumes glow at every collision.
+    // absorption is a uniform background on top of the density, from the glass around it
+    Heterogeneous { density: Arc<DensityField>, scale: f64, majorant: f64, albedo: Color, g: f64, emission: Option<Arc<VolumeEmission>>, absorption: Color },
+    // Absorbs without scattering, like the inside of tinted glass. Attenuation is applied exactly instead of sampled
+    Absorbing { sigma_a: Color }
+}
+
+impl Medium {
+    pub fn new_homogeneous(sigma_t: Color, albedo: Color) -> Medium {
+        Medium::Homogeneous { sigma_t, albedo, emission: None }
+    }
+
+    // Subsurface interior given the average distance light travels between interactions in each channel
+    pub fn new_subsurface(albedo: Color, mean_free_path: Color) -> Medium {
+        Self::new_homogeneous(Color::new(1.0 / mean_free_path.x, 1.0 / mean_free_path.y, 1.0 / mean_free_path.z), albedo)
+    }
+
+    pub fn new_heterogeneous(density: DensityField, scale: f64, albedo: Color, g: f64) -> Medium {
+        let majorant = scale * density.max_value();
+        Medium::Heterogeneous { density: Arc::new(density), scale, majorant, albedo, g, emission: None, absorption: Color::new(0.0, 0.0, 0.0) }
+    }
+
+    pub fn with_emission(self, emission: VolumeEmission) -> Medium {
+        match self {
+            Medium::Homogeneous { sigma_t, albedo, emission: _ } => Medium::Homogeneous { sigma_t, albedo, emission: Some(Arc::new(emission)) },
+            Medium::Heterogeneous { density, scale, majorant, albedo, g, emission: _, absorption } => {
+                Medium::Heterogeneous { density, scale, majorant, albedo, g, emission: Some(Arc::new(emission)), absorption }
+            },
+            _ => self
+        }
+    }
+
+    // The same medium inside of glass that also absorbs with the given coefficients
+    pub fn with_absorption(self, sigma_a: Color) -> Medium {
+        match self {
+            Medium::Homogeneous { sigma_t, albedo, emission } => {
+                // Absorption adds to extinction, and scattering keeps its share of the total
+                let total = sigma_t + sigma_a;
+                let share = |albedo: f64, sigma_t: f64, total: f64| if total > 0.0 { albedo * sigma_t / total } else { albedo };
+                Medium::Homogeneous {
+                    sigma_t: total,
+                    albedo: Color::new(share(albedo.x, sigma_t.x, total.x), share(albedo.y, sigma_t.y, total.y), share(albedo.z, sigma_t.z, total.z)),
+                    emission
+                }
+            },
+            Medium::Heterogeneous { density, scale, majorant, albedo, g, emission, absorption } => {
+                Medium::Heterogeneous { density, scale, majorant, albedo, g, emission, absorption: absorption + sigma_a }
+            },
+            Medium::Absorbing { sigma_a: absorption } => Medium::Absorbing { sigma_a: absorption + sigma_a }
+        }
+    }
+
+    // Direction of scattering after a collision. The albedo is already applied by sample()
+    pub fn phase_function(&self) -> Material {
+        match self {
+            Medium::Homogeneous { sigma_t: _, albedo: _, emission: _ } => Material::Isotropic { albedo: Texture::SolidColor(Color::new(1.0, 1.0, 1.0)) },
+            Medium::Heterogeneous { density: _, scale: _, majorant: _, albedo: _, g, emission: _, absorption: _ } => {
+                Material::HenyeyGreenstein { albedo: Texture::SolidColor(Color::new(1.0, 1.0, 1.0)), g: *g }
+            },
+            // Never sampled, since nothing scatters
+            Medium::Absorbing { sigma_a: _ } => Material::Isotropic { albedo: Texture::SolidColor(Color::new(1.0, 1.0, 1.0)) }
+        }
+    }
+
+    // Radiance emitted at a collision, at the wavelengths of a spectral path if there are any
+    pub fn emitted(&self, p: &Point3, wavelengths: &Option<Vector3>) -> Color {
+        let emission = match self {
+            Medium::Homogeneous { sigma_t: _, albedo: _, emission: Some(emission) } => emission,
+            Medium::Heterogeneous { density: _, scale: _, majorant: _, albedo: _, g: _, emission: Some(emission), absorption: _ } => emission,
+            _ => { return Color::new(0.0, 0.0, 0.0); }
+        };
+
+        match wavelengths {
+            Some(wavelengths) => emission.value_samples(p, wavelengths),
+            None => emission.value(p)
+        }
+    }
+
+    // Coefficients at the wavelengths of a spectral path instead of RGB
+    pub fn to_wavelengths(&self, wavelengths: &Vector3) -> Medium {
+        match self {
+            Medium::Homogeneous { sigma_t, albedo, emission } => {
+                Medium::Homogeneous {
+                    sigma_t: Self::coefficient_samples(sigma_t, wavelengths),
+                    albedo: rgb_to_reflectance_samples(albedo, wavelengths),
+                    emission: emission.clone()
+                }
+            },
+            Medium::Heterogeneous { density, scale, majorant, albedo, g, emission, absorption } => {
+                Medium::Heterogeneous {
+                    density: density.clone(),
+                    scale: *scale,
+                    majorant: *majorant,
+                    albedo: rgb_to_reflectance_samples(albedo, wavelengths),
+                    g: *g,
+                    emission: emission.clone(),
+                    absorption: Self::coefficient_samples(absorption, wavelengths)
+                }
+            },
+            Medium::Absorbing { sigma_a } => Medium::Absorbing { sigma_a: Self::coefficient_samples(sigma_a, wavelengths) }
+        }
+    }
+
+    // Upsampled as a reflectance relative to the largest channel, since coefficients aren't bounded by one
+    fn coefficient_samples(coefficient: &Color, wavelengths: &Vector3) -> Color {
+        let scale = coefficient.x.max(coefficient.y).max(coefficient.z);
+        if scale <= 0.0 {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+
+        scale * rgb_to_reflectance_samples(&(*coefficient / scale), wavelengths)
+    }
+
+    // Samples the next scattering event along the ray before t_max, with distances drawn from the given channel's
+    // density. Returns the ray parameter of the event, if the ray doesn't get through, and the segment's throughput
+    // and per-channel pdf, which the integrator combines over all channels
+    pub fn sample(&self, ray: &Ray, t_max: f64, channel: usize) -> (Option<f64>, Color, Color) {
+        match self {
+            Medium::Homogeneous { sigma_t, albedo, emission: _ } => {
+                let sigma = [sigma_t.x, sigma_t.y, sigma_t.z][channel];
+
+                let ray_length = ray.direction.length();
+                let distance = -(1.0 - random_double()).ln() / sigma;
+                let max_distance = t_max * ray_length;
+
+                if distance < max_distance {
+                    let pdf = *sigma_t * Self::beer_lambert(sigma_t, distance);
+                    (Some(distance / ray_length), *albedo * pdf, pdf)
+                } else {
+                    let transmittance = Self::beer_lambert(sigma_t, max_distance);
+                    (None, transmittance, transmittance)
+                }
+            },
+            Medium::Heterogeneous { density, scale, majorant, albedo, g: _, emission: _, absorption } => {
+                // Delta tracking: tentative collisions are sampled against the majorant, and each is a real one with
+                // probability density / majorant, otherwise the ray carries on. Extinction is the same in every
+                // channel, so the pdfs cancel and a real collision only weighs the path by the albedo. Background
+                // absorption is applied exactly over the distance travelled
+                let one = Color::new(1.0, 1.0, 1.0);
+                // A ray that never leaves the medium can't get through it
+                if t_max == INFINITY {
+                    return (None, Color::new(0.0, 0.0, 0.0), one);
+                }
+
+                let ray_length = ray.direction.length();
+                if *majorant <= 0.0 {
+                    return (None, Self::beer_lambert(absorption, t_max * ray_length), one);
+                }
+
+                let mut t = 0.0;
+                loop {
+                    t -= (1.0 - random_double()).ln() / (majorant * ray_length);
+                    if t >= t_max {
+                        return (None, Self::beer_lambert(absorption, t_max * ray_length), one);
+                    }
+
+                    if random_double() * majorant < scale * density.value(&ray.at(t)) {
+                        return (Some(t), *albedo * Self::beer_lambert(absorption, t * ray_length), one);
+                    }
+                }
+            },
+            Medium::Absorbing { sigma_a } => {
+                (None, Self::beer_lambert(sigma_a, t_max * ray.direction.length()), Color::new(1.0, 1.0, 1.0))
+            }
+        }
+    }
+
+    // Fraction of light getting through the medium along the ray up to t_max, for shadow rays
+    pub fn transmittance(&self, ray: &Ray, t_max: f64) -> Color {
+        match self {
+            Medium::Homogeneous { sigma_t, albedo: _, emission: _ } => {
+                Self::beer_lambert(sigma_t, t_max * ray.direction.length())
+            },
+            Medium::Heterogeneous { density, scale, majorant, albedo: _, g: _, emission: _, absorption } => {
+                // Ratio tracking: each tentative collision lets through the fraction of the majorant that is null
+                if t_max == INFINITY {
+                    return Color::new(0.0, 0.0, 0.0);
+                }
+
+                let ray_length = ray.direction.length();
+                let absorbed = Self::beer_lambert(absorption, t_max * ray_length);
+                if *majorant <= 0.0 {
+                    return absorbed;
+                }
+
+                let mut transmittance = 1.0;
+                let mut t = 0.0;
+                loop {
+                    t -= (1.0 - random_double()).ln() / (majorant * ray_length);
+                    if t >= t_max || transmittance <= 0.0 {
+                        return transmittance * absorbed;
+                    }
+
+                    transmittance *= 1.0 - (scale * density.value(&ray.at(t)) / majorant).min(1.0);
+                }
+            },
+            Medium::Absorbing { sigma_a } => {
+                Self::beer_lambert(sigma_a, t_max * ray.direction.length())
+            }
+        }
+    }
+
+    fn beer_lambert(sigma_t: &Color, distance: f64) -> Color {
+        Color::new((-sigma_t.x * distance).exp(), (-sigma_t.y * distance).exp(), (-sigma_t.z * distance).exp())
+    }
+}
+
+// Media a path is inside of, innermost last. Entries are keyed by the material of the boundary crossed to enter
+// them, so leaving a boundary removes its own entry even if boundaries overlap instead of being nested. Objects
+// without an interior medium, like plain glass, get an empty entry since nothing scatters inside of them
+#[derive(Clone, Default)]
+pub struct MediumStack {
+    entries: Vec<(MaterialHandle, Option<Medium>)>
+}
+
+impl MediumStack {
+    pub fn new() -> MediumStack {
+        MediumStack { entries: Vec::new() }
+    }
+
+    pub fn current(&self) -> Option<&Medium> {
+        self.entries.last().and_then(|(_, medium)| medium.as_ref())
+    }
+
+    pub fn enter(&mut self, boundary: MaterialHandle, medium: Option<Medium>) {
+        self.entries.push((boundary, medium));
+    }
+
+    pub fn exit(&mut self, boundary: MaterialHandle) {
+        if let Some(index) = self.entries.iter().rposition(|(entered, _)| entered.0 == boundary.0) {
+            self.entries.remove(index);
+        }
+    }
+
+    pub fn to_wavelengths(&self, wavelengths: &Vector3) -> MediumStack {
+        MediumStack {
+            entries: self.entries.iter().map(|(boundary, medium)| (*boundary, medium.as_ref().map(|medium| medium.to_wavelengths(wavelengths)))).collect()
+        }
+    }
+}
+
+// Light emitted by a volume at each collision along a ray. Emission is the radiance of an optically thick region,
+// and thinner regions glow in proportion to their optical depth
+pub enum VolumeEmission {
+    // Radiance read from a solid texture at the collision point
+    Texture(Texture),
+    // Blackbody radiation at temperatures proportional to the field's value, with the hottest point at max_temperature
+    // and radiance scaled by normalization. RGB is interpolated from a table over the temperature range
+    Temperature { field: DensityField, max_temperature: f64, normalization: f64, table: Vec<Color> }
+}
+
+impl VolumeEmission {
+    const TEMPERATURE_STEPS: usize = 256;
+
+    // Fire or glowing gas whose hottest point, at max_temperature in Kelvin, has the given luminance
+    pub fn new_temperature(field: DensityField, max_temperature: f64, luminance: f64) -> VolumeEmission {
+        let normalization = luminance / blackbody_xyz(max_temperature).y;
+
+        let table = (0..=Self::TEMPERATURE_STEPS)
+            .map(|i| xyz_to_rgb(&blackbody_xyz(max_temperature * i as f64 / Self::TEMPERATURE_STEPS as f64)) * normalization)
+            .collect();
+
+        VolumeEmission::Temperature { field, max_temperature, normalization, table }
+    }
+
+    pub fn value(&self, p: &Point3) -> Color {
+        match self {
+            VolumeEmission::Texture(texture) => texture.get_color_value(0.0, 0.0, p),
+            VolumeEmission::Temperature { field, max_temperature: _, normalization: _, table } => {
+                let x = clamp(Self::relative_temperature(field, p), 0.0, 1.0) * Self::TEMPERATURE_STEPS as f64;
+                let i = (x as usize).min(Self::TEMPERATURE_STEPS - 1);
+                let t = x - i as f64;
+                (1.0 - t) * table[i] + t * table[i + 1]
+            }
+        }
+    }
+
+    // Emission at the three wavelengths carried by a spectral path
+    pub fn value_samples(&self, p: &Point3, wavelengths: &Vector3) -> Color {
+        match self {
+            VolumeEmission::Texture(texture) => rgb_to_illuminant_samples(&texture.get_color_value(0.0, 0.0, p), wavelengths),
+            VolumeEmission::Temperature { field, max_temperature, normalization, table: _ } => {
+                let temperature = clamp(Self::relative_temperature(field, p), 0.0, 1.0) * max_temperature;
+                if temperature <= 0.0 {
+                    return Color::new(0.0, 0.0, 0.0);
+                }
+
+                *normalization * Color::new(planck(wavelengths.x, temperature), planck(wavelengths.y, temperature), planck(wavelengths.z, temperature))
+            }
+        }
+    }
+
+    fn relative_temperature(field: &DensityField, p: &Point3) -> f64 {
+        let max = field.max_value();
+        if max > 0.0 { field.value(p) / max } else { 0.0 }
+    }
+}
+
+// Spatially varying density for heterogeneous media
+pub enum DensityField {
+    // Voxel values stored x fastest, then y, then z, trilinearly interpolated between voxel centers and stretched
+    // over bounds. Density is zero outside of bounds
+    Grid { nx: usize, ny: usize, nz: usize, data: Vec<f32>, bounds: AABB, max: f64 },
+    // VDB style sparse grid of 8x8x8 leaf blocks keyed by their index space origin. Voxels without a leaf hold the
+    // background value, and the inclusive index bounding box is stretched over bounds
+    Sparse { leaves: HashMap<(i32, i32, i32), Box<[f32; 512]>>, background: f32, index_min: [i32; 3], index_max: [i32; 3], bounds: AABB, max: f64 },
+    // Perlin turbulence of the point scaled by frequency
+    Turbulence { perlin: Perlin, frequency: f64 }
+}
+
+impl DensityField {
+    pub fn new_grid(nx: usize, ny: usize, nz: usize, data: Vec<f32>, bounds: AABB) -> DensityField {
+        assert_eq!(data.len(), nx * ny * nz, "Density grid size doesn't match its dimensions");
+
+        let max = data.iter().fold(0.0_f32, |max, d| max.max(*d)) as f64;
+        DensityField::Grid { nx, ny, nz, data, bounds, max }
+    }
+
+    // Loads a density grid file and places it in bounds. Supported are Mitsuba style dense .vol grids and the
+    // sparse .svol layout read by load_sparse
+    pub fn load(path: &str, bounds: AABB) -> DensityField {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(err) => {
+                panic!("{}: {}", path, err);
+            }
+        };
+
+        if path.ends_with(".svol") {
+            Self::load_sparse(ByteReader::new(data), bounds)
+        } else {
+            Self::load_dense(ByteReader::new(data), bounds)
+        }
+    }
+
+    // Header: "VOL" and version 3, encoding (1 = f32, 3 = u8), x/y/z resolution and channel count as i32, followed
+    // by the file's bounding box as six f32, which is replaced by the given bounds. Voxels follow x fastest, and
+    // only the first channel is used
+    fn load_dense(mut reader: ByteReader, bounds: AABB) -> DensityField {
+        if reader.bytes(3) != b"VOL" || reader.u8() != 3 {
+            panic!("Not a version 3 .vol grid");
+        }
+
+        let encoding = reader.i32();
+        let nx = reader.i32() as usize;
+        let ny = reader.i32() as usize;
+        let nz = reader.i32() as usize;
+        let channels = reader.i32() as usize;
+        for _ in 0..6 {
+            reader.f32();
+        }
+
+        let mut data = Vec::with_capacity(nx * ny * nz);
+        for _ in 0..nx * ny * nz {
+            let value = match encoding {
+                1 => reader.f32(),
+                3 => reader.u8() as f32 / 255.0,
+                _ => { panic!("Unsupported .vol encoding {}", encoding) }
+            };
+            data.push(value);
+
+            for _ in 1..channels {
+                match encoding {
+                    1 => { reader.f32(); },
+                    _ => { reader.u8(); }
+                }
+            }
+        }
+
+        Self::new_grid(nx, ny, nz, data, bounds)
+    }
+
+    // Float grid modelled on NanoVDB leaf nodes, all values little endian. Header: "SVOL", version 1 as u32, the
+    // inclusive index bounding box as six i32, the background value as f32 and the leaf count as u32. Each leaf is
+    // its origin as three i32 (multiples of 8), an active voxel mask of 8 u64, and 512 f32 values ordered like VDB
+    // leaves with z fastest. Inactive voxels read as the background
+    fn load_sparse(mut reader: ByteReader, bounds: AABB) -> DensityField {
+        if reader.bytes(4) != b"SVOL" || reader.u32() != 1 {
+            panic!("Not a version 1 .svol grid");
+        }
+
+        let index_min = [reader.i32(), reader.i32(), reader.i32()];
+        let index_max = [reader.i32(), reader.i32(), reader.i32()];
+        let background = reader.f32();
+        let leaf_count = reader.u32();
+
+        let mut leaves = HashMap::new();
+        let mut max = background.max(0.0);
+        for _ in 0..leaf_count {
+            let origin = (reader.i32(), reader.i32(), reader.i32());
+            if origin.0 % 8 != 0 || origin.1 % 8 != 0 || origin.2 % 8 != 0 {
+                panic!("Leaf origin {:?} isn't aligned to 8 voxels", origin);
+            }
+
+            let mut mask = [0_u64; 8];
+            for word in mask.iter_mut() {
+                *word = reader.u64();
+            }
+
+            let mut values = Box::new([background; 512]);
+            for (n, value) in values.iter_mut().enumerate() {
+                let v = reader.f32();
+                if mask[n / 64] & (1 << (n % 64)) != 0 {
+                    *value = v;
+                    max = max.max(v);
+                }
+            }
+
+            leaves.insert(origin, values);
+        }
+
+        DensityField::Sparse { leaves, background, index_min, index_max, bounds, max: max as f64 }
+    }
+
+    pub fn new_turbulence(frequency: f64) -> DensityField {
+        DensityField::Turbulence { perlin: Perlin::new(), frequency }
+    }
+
+    pub fn value(&self, p: &Point3) -> f64 {
+        match self {
+            DensityField::Grid { nx, ny, nz, data, bounds, max: _ } => {
+                let index = match grid_index(p, bounds, [0, 0, 0], [*nx as i32 - 1, *ny as i32 - 1, *nz as i32 - 1]) {
+                    Some(index) => index,
+                    None => { return 0.0; }
+                };
+
+                trilinear(&index, |i, j, k| {
+                    let i = i.clamp(0, *nx as i32 - 1) as usize;
+                    let j = j.clamp(0, *ny as i32 - 1) as usize;
+                    let k = k.clamp(0, *nz as i32 - 1) as usize;
+                    data[(k * *ny + j) * *nx + i]
+                })
+            },
+            DensityField::Sparse { leaves, background, index_min, index_max, bounds, max: _ } => {
+                let index = match grid_index(p, bounds, *index_min, *index_max) {
+                    Some(index) => index,
+                    None => { return 0.0; }
+                };
+
+                trilinear(&index, |i, j, k| {
+                    let i = i.clamp(index_min[0], index_max[0]);
+                    let j = j.clamp(index_min[1], index_max[1]);
+                    let k = k.clamp(index_min[2], index_max[2]);
+                    match leaves.get(&(i & !7, j & !7, k & !7)) {
+                        Some(leaf) => leaf[(((i & 7) << 6) | ((j & 7) << 3) | (k & 7)) as usize],
+                        None => *background
+                    }
+                })
+            },
+            DensityField::Turbulence { perlin, frequency } => {
+                perlin.turb(&(*frequency * *p), 7)
+            }
+        }
+    }
+
+    // Upper bound of value() anywhere, used as the majorant for delta tracking
+    pub fn max_value(&self) -> f64 {
+        match self {
+            DensityField::Grid { nx: _, ny: _, nz: _, data: _, bounds: _, max } => *max,
+            DensityField::Sparse { leaves: _, background: _, index_min: _, index_max: _, bounds: _, max } => *max,
+            // turb sums octaves of |noise| with halving weights
+            DensityField::Turbulence { perlin: _, frequency: _ } => 2.0
+        }
+    }
+}
+
+// Continuous index space position of a point, with voxel centers at integer coordinates from index_min to index_max
+// stretched over bounds. None outside of the bounds
+fn grid_index(p: &Point3, bounds: &AABB, index_min: [i32; 3], index_max: [i32; 3]) -> Option<[f64; 3]> {
+    let extent = bounds.maximum - bounds.minimum;
+    let local = *p - bounds.minimum;
+    let relative = [local.x / extent.x, local.y / extent.y, local.z / extent.z];
+
+    let mut index = [0.0; 3];
+    for axis in 0..3 {
+        if !(0.0..=1.0).contains(&relative[axis]) {
+            return None;
+        }
+
+        let resolution = (index_max[axis] - index_min[axis] + 1) as f64;
+        index[axis] = index_min[axis] as f64 + relative[axis] * resolution - 0.5;
+    }
+
+    Some(index)
+}
+
+fn trilinear<F: Fn(i32, i32, i32) -> f32>(index: &[f64; 3], voxel: F) -> f64 {
+    let (x0, y0, z0) = (index[0].floor(), index[1].floor(), index[2].floor());
+    let (tx, ty, tz) = (index[0] - x0, index[1] - y0, index[2] - z0);
+    let (i, j, k) = (x0 as i32, y0 as i32, z0 as i32);
+
+    let mut value = 0.0;
+    for (di, wx) in [(0, 1.0 - tx), (1, tx)] {
+        for (dj, wy) in [(0, 1.0 - ty), (1, ty)] {
+            for (dk, wz) in [(0, 1.0 - tz), (1, tz)] {
+                value += wx * wy * wz * voxel(i + di, j + dj, k + dk) as f64;
+            }
+        }
+    }
+
+    value
+}
+
+// Little endian reader over a whole grid file, panicking if it ends early
+struct ByteReader {
+    data: Vec<u8>,
+    offset: usize
+}
+
+impl ByteReader {
+    fn new(data: Vec<u8>) -> ByteReader {
+        ByteReader { data, offset: 0 }
+    }
+
+    fn bytes(&mut self, count: usize) -> &[u8] {
+        if self.offset + count > self.data.len() {
+            panic!("Grid file ended unexpectedly");
+        }
+
+        self.offset += count;
+        &self.data[self.offset - count..self.offset]
+    }
+
+    fn array<const N: usize>(&mut self) -> [u8; N] {
+        let mut array = [0; N];
+        array.copy_from_slice(self.bytes(N));
+        array
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.bytes(1)[0]
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.array())
+    }
+
+    fn i32(&mut self) -> i32 {
+        i32::from_le_bytes(self.array())
+    }
+
+    fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.array())
+    }
+
+    fn f32(&mut self) -> f32 {
+        f32::from_le_bytes(self.array())
+    }
+}
+
+// Henyey-Greenstein phase function for the angle between the propagation direction and the scattered direction.
+// g in (-1, 1) is the mean cosine: positive values scatter forward, negative ones back, and 0 is isotropic
+pub fn henyey_greenstein(cos_theta: f64, g: f64) -> f64 {
+    let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * PI * denom * denom.sqrt())
+}
+
+// Samples a scattered direction proportionally to the phase function around the given propagation direction
+pub fn sample_henyey_greenstein(direction: &Vector3, g: f64) -> Vector3 {
+    let cos_theta = if g.abs() < 1e-3 {
+        1.0 - 2.0 * random_double()
+    } else {
+        let sqr_term = (1.0 - g * g) / (1.0 + g - 2.0 * g * random_double());
+        (1.0 + g * g - sqr_term * sqr_term) / (2.0 * g)
+    };
+
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * random_double();
+
+    let uvw = ONB::build_from_w(&Vector3::normalize(direction));
+    uvw.local(&Vector3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta))
+}
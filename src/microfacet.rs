@@ -0,0 +1,248 @@
+use crate::math::*;
+
+// Trowbridge-Reitz (GGX) microfacet distribution. All directions are in the local shading frame,
+// where the surface normal is +Z.
+#[derive(Copy, Clone)]
+pub struct TrowbridgeReitz {
+    pub alpha_x: f64,
+    pub alpha_y: f64
+}
+
+impl TrowbridgeReitz {
+    // Each alpha is kept above zero, as a surface smooth along one axis only would divide by zero in d()
+    pub fn new(alpha_x: f64, alpha_y: f64) -> TrowbridgeReitz {
+        TrowbridgeReitz {
+            alpha_x: alpha_x.max(1e-4),
+            alpha_y: alpha_y.max(1e-4)
+        }
+    }
+
+    // Maps perceptual roughness in [0,1] to the distribution's alpha
+    pub fn roughness_to_alpha(roughness: f64) -> f64 {
+        roughness * roughness
+    }
+
+    // Below this the distribution is treated as a perfect mirror
+    pub fn effectively_smooth(&self) -> bool {
+        self.alpha_x.max(self.alpha_y) < 1e-3
+    }
+
+    pub fn d(&self, wm: &Vector3) -> f64 {
+        let x = wm.x / self.alpha_x;
+        let y = wm.y / self.alpha_y;
+        let e = x * x + y * y + wm.z * wm.z;
+
+        1.0 / (PI * self.alpha_x * self.alpha_y * e * e)
+    }
+
+    pub fn lambda(&self, w: &Vector3) -> f64 {
+        if w.z == 0.0 {
+            return INFINITY;
+        }
+
+        let alpha2_tan2_theta = (self.alpha_x * self.alpha_x * w.x * w.x + self.alpha_y * self.alpha_y * w.y * w.y) / (w.z * w.z);
+        ((1.0 + alpha2_tan2_theta).sqrt() - 1.0) / 2.0
+    }
+
+    pub fn g1(&self, w: &Vector3) -> f64 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    // Height-correlated Smith masking-shadowing
+    pub fn g(&self, wo: &Vector3, wi: &Vector3) -> f64 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    // Distribution of normals visible from w
+    pub fn d_visible(&self, w: &Vector3, wm: &Vector3) -> f64 {
+        if w.z == 0.0 {
+            return 0.0;
+        }
+
+        self.g1(w) / w.z.abs() * self.d(wm) * Vector3::dot(w, wm).abs()
+    }
+
+    // Samples a visible normal following Heitz, "Sampling the GGX Distribution of Visible Normals"
+    pub fn sample_wm(&self, w: &Vector3) -> Vector3 {
+        // Transform w to the hemispherical configuration
+        let mut wh = Vector3::normalize(&Vector3::new(self.alpha_x * w.x, self.alpha_y * w.y, w.z));
+        if wh.z < 0.0 {
+            wh = -wh;
+        }
+
+        let t1 = if wh.z < 0.99999 {
+            Vector3::normalize(&Vector3::cross(&Vector3::new(0.0, 0.0, 1.0), &wh))
+        } else {
+            Vector3::new(1.0, 0.0, 0.0)
+        };
+        let t2 = Vector3::cross(&wh, &t1);
+
+        // Uniformly sample a disk and warp it onto the visible hemisphere
+        let r = random_double().sqrt();
+        let phi = 2.0 * PI * random_double();
+        let px = r * phi.cos();
+        let mut py = r * phi.sin();
+
+        let h = (1.0 - px * px).sqrt();
+        let s = (1.0 + wh.z) / 2.0;
+        py = (1.0 - s) * h + s * py;
+
+        let pz = (1.0 - px * px - py * py).max(0.0).sqrt();
+        let nh = px * t1 + py * t2 + pz * wh;
+
+        Vector3::normalize(&Vector3::new(self.alpha_x * nh.x, self.alpha_y * nh.y, nh.z.max(1e-6)))
+    }
+}
+
+// Exact Fresnel reflectance of a conductor with complex index of refraction eta + ik, seen from air
+pub fn fresnel_conductor(cos_theta_i: f64, eta: f64, k: f64) -> f64 {
+    let cos2 = clamp(cos_theta_i, 0.0, 1.0).powi(2);
+    let sin2 = 1.0 - cos2;
+    let eta2 = eta * eta;
+    let k2 = k * k;
+
+    let t0 = eta2 - k2 - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t2 = 2.0 * a * cos_theta_i;
+    let rs = (t1 - t2) / (t1 + t2);
+
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+
+    0.5 * (rp + rs)
+}
+
+// Exact unpolarized Fresnel reflectance at a dielectric interface. eta is the index of refraction on the
+// side opposite to the normal over the index on the normal's side, cos_theta_i is measured against that normal.
+pub fn fresnel_dielectric(cos_theta_i: f64, eta: f64) -> f64 {
+    let mut cos_theta_i = clamp(cos_theta_i, -1.0, 1.0);
+    let mut eta = eta;
+
+    // Flip the interface orientation when the direction is on the other side
+    if cos_theta_i < 0.0 {
+        eta = 1.0 / eta;
+        cos_theta_i = -cos_theta_i;
+    }
+
+    let sin2_theta_i = 1.0 - cos_theta_i * cos_theta_i;
+    let sin2_theta_t = sin2_theta_i / (eta * eta);
+    if sin2_theta_t >= 1.0 {
+        // Total internal reflection
+        return 1.0;
+    }
+
+    let cos_theta_t = (1.0 - sin2_theta_t).max(0.0).sqrt();
+    let r_parallel = (eta * cos_theta_i - cos_theta_t) / (eta * cos_theta_i + cos_theta_t);
+    let r_perpendicular = (cos_theta_i - eta * cos_theta_t) / (cos_theta_i + eta * cos_theta_t);
+
+    (r_parallel * r_parallel + r_perpendicular * r_perpendicular) / 2.0
+}
+
+pub fn fresnel_conductor_color(cos_theta_i: f64, eta: &Color, k: &Color) -> Color {
+    Color::new(
+        fresnel_conductor(cos_theta_i, eta.x, k.x),
+        fresnel_conductor(cos_theta_i, eta.y, k.y),
+        fresnel_conductor(cos_theta_i, eta.z, k.z)
+    )
+}
+
+pub fn reflect_local(wo: &Vector3, n: &Vector3) -> Vector3 {
+    -wo + 2.0 * Vector3::dot(wo, n) * n
+}
+
+// Refracts w about n (both pointing away from the surface on the same side), with eta defined as in
+// fresnel_dielectric. Returns the transmitted direction and the relative index actually used.
+pub fn refract_local(w: &Vector3, n: &Vector3, eta: f64) -> Option<(Vector3, f64)> {
+    let mut cos_theta_i = Vector3::dot(n, w);
+    let mut eta = eta;
+    let mut n = *n;
+
+    if cos_theta_i < 0.0 {
+        eta = 1.0 / eta;
+        cos_theta_i = -cos_theta_i;
+        n = -n;
+    }
+
+    let sin2_theta_i = (1.0 - cos_theta_i * cos_theta_i).max(0.0);
+    let sin2_theta_t = sin2_theta_i / (eta * eta);
+    if sin2_theta_t >= 1.0 {
+        return None;
+    }
+
+    let cos_theta_t = (1.0 - sin2_theta_t).sqrt();
+    Some((-w / eta + (cos_theta_i / eta - cos_theta_t) * n, eta))
+}
+
+// Generalized half vector for reflection and refraction, facing +Z. None for degenerate configurations
+// or when the microfacet would be back-facing for either direction.
+pub fn dielectric_half_vector(eta: f64, wo: &Vector3, wi: &Vector3) -> Option<(Vector3, f64)> {
+    if wo.z == 0.0 || wi.z == 0.0 {
+        return None;
+    }
+
+    let reflect = wi.z * wo.z > 0.0;
+    let etap = if reflect { 1.0 } else if wo.z > 0.0 { eta } else { 1.0 / eta };
+
+    let wm = *wi * etap + *wo;
+    if wm.near_zero() {
+        return None;
+    }
+
+    let mut wm = Vector3::normalize(&wm);
+    if wm.z < 0.0 {
+        wm = -wm;
+    }
+
+    if Vector3::dot(&wm, wi) * wi.z < 0.0 || Vector3::dot(&wm, wo) * wo.z < 0.0 {
+        return None;
+    }
+
+    Some((wm, etap))
+}
+
+// BSDF times |cos_i| of a rough dielectric interface, covering both reflection and transmission
+pub fn rough_dielectric_eval(eta: f64, distribution: &TrowbridgeReitz, wo: &Vector3, wi: &Vector3) -> f64 {
+    if distribution.effectively_smooth() {
+        return 0.0;
+    }
+
+    let (wm, etap) = match dielectric_half_vector(eta, wo, wi) {
+        Some(half) => half,
+        None => return 0.0
+    };
+
+    let fresnel = fresnel_dielectric(Vector3::dot(wo, &wm), eta);
+
+    let value = if wi.z * wo.z > 0.0 {
+        distribution.d(&wm) * distribution.g(wo, wi) * fresnel / (4.0 * wi.z * wo.z).abs()
+    } else {
+        let denom = (Vector3::dot(wi, &wm) + Vector3::dot(wo, &wm) / etap).powi(2) * wi.z * wo.z;
+        distribution.d(&wm) * (1.0 - fresnel) * distribution.g(wo, wi) * (Vector3::dot(wi, &wm) * Vector3::dot(wo, &wm) / denom).abs() / (etap * etap)
+    };
+
+    value * wi.z.abs()
+}
+
+pub fn rough_dielectric_pdf(eta: f64, distribution: &TrowbridgeReitz, wo: &Vector3, wi: &Vector3) -> f64 {
+    if distribution.effectively_smooth() {
+        return 0.0;
+    }
+
+    let (wm, etap) = match dielectric_half_vector(eta, wo, wi) {
+        Some(half) => half,
+        None => return 0.0
+    };
+
+    let reflectance = fresnel_dielectric(Vector3::dot(wo, &wm), eta);
+
+    if wi.z * wo.z > 0.0 {
+        distribution.d_visible(wo, &wm) / (4.0 * Vector3::dot(wo, &wm).abs()) * reflectance
+    } else {
+        let denom = (Vector3::dot(wi, &wm) + Vector3::dot(wo, &wm) / etap).powi(2);
+        let dwm_dwi = Vector3::dot(wi, &wm).abs() / denom;
+        distribution.d_visible(wo, &wm) * dwm_dwi * (1.0 - reflectance)
+    }
+}
@@ -0,0 +1,32 @@
+use crate::math::*;
+
+// Orthonormal basis, used to move directions between a local frame (w = normal) and world space
+pub struct ONB {
+    pub u: Vector3,
+    pub v: Vector3,
+    pub w: Vector3
+}
+
+impl ONB {
+    pub fn build_from_w(n: &Vector3) -> ONB {
+        let w = Vector3::normalize(n);
+        let a = if w.x.abs() > 0.9 { Vector3::new(0.0, 1.0, 0.0) } else { Vector3::new(1.0, 0.0, 0.0) };
+        let v = Vector3::normalize(&Vector3::cross(&w, &a));
+        let u = Vector3::cross(&w, &v);
+
+        ONB {
+            u,
+            v,
+            w
+        }
+    }
+
+    pub fn local(&self, a: &Vector3) -> Vector3 {
+        a.x * self.u + a.y * self.v + a.z * self.w
+    }
+
+    // Inverse of local(), expresses a world space direction in this basis
+    pub fn to_local(&self, a: &Vector3) -> Vector3 {
+        Vector3::new(Vector3::dot(a, &self.u), Vector3::dot(a, &self.v), Vector3::dot(a, &self.w))
+    }
+}
@@ -0,0 +1,253 @@
+use crate::math::*;
+use crate::ray::*;
+use crate::hittable::*;
+use crate::texture::*;
+use crate::onb::*;
+use crate::microfacet::*;
+use crate::material::ScatterRecord;
+
+// Disney-style principled BSDF. Scalar parameters are read from their texture's luminance.
+pub struct Principled {
+    pub base_color: Texture,
+    pub metallic: Texture,
+    pub roughness: Texture,
+    pub specular: Texture, // Scales the dielectric reflectance, 0.5 gives the common 4%
+    pub sheen: Texture,
+    pub clearcoat: Texture,
+    pub clearcoat_gloss: Texture,
+    pub transmission: Texture,
+    pub ior: f64
+}
+
+// Parameters evaluated at a hit point
+struct PrincipledParams {
+    base_color: Color,
+    metallic: f64,
+    roughness: f64,
+    specular: f64,
+    sheen: f64,
+    clearcoat: f64,
+    clearcoat_gloss: f64,
+    transmission: f64
+}
+
+// Probabilities of picking each lobe when sampling
+struct LobeWeights {
+    diffuse: f64,
+    specular: f64,
+    clearcoat: f64,
+    transmission: f64
+}
+
+impl Principled {
+    pub fn new(base_color: Texture) -> Principled {
+        Principled {
+            base_color,
+            metallic: Texture::SolidColor(Color::new(0.0, 0.0, 0.0)),
+            roughness: Texture::SolidColor(Color::new(0.5, 0.5, 0.5)),
+            specular: Texture::SolidColor(Color::new(0.5, 0.5, 0.5)),
+            sheen: Texture::SolidColor(Color::new(0.0, 0.0, 0.0)),
+            clearcoat: Texture::SolidColor(Color::new(0.0, 0.0, 0.0)),
+            clearcoat_gloss: Texture::SolidColor(Color::new(1.0, 1.0, 1.0)),
+            transmission: Texture::SolidColor(Color::new(0.0, 0.0, 0.0)),
+            ior: 1.5
+        }
+    }
+
+    fn params(&self, rec: &HitRecord) -> PrincipledParams {
+        PrincipledParams {
+            base_color: self.base_color.get_hit_color_value(rec),
+            metallic: clamp(self.metallic.get_hit_scalar_value(rec), 0.0, 1.0),
+            // Keep a little roughness so no lobe degenerates into a delta distribution
+            roughness: clamp(self.roughness.get_hit_scalar_value(rec), 0.05, 1.0),
+            specular: self.specular.get_hit_scalar_value(rec).max(0.0),
+            sheen: self.sheen.get_hit_scalar_value(rec).max(0.0),
+            clearcoat: self.clearcoat.get_hit_scalar_value(rec).max(0.0),
+            clearcoat_gloss: clamp(self.clearcoat_gloss.get_hit_scalar_value(rec), 0.0, 1.0),
+            transmission: clamp(self.transmission.get_hit_scalar_value(rec), 0.0, 1.0)
+        }
+    }
+
+    fn lobe_weights(params: &PrincipledParams) -> Option<LobeWeights> {
+        let dielectric = 1.0 - params.metallic;
+
+        let diffuse = dielectric * (1.0 - params.transmission) * luminance(&params.base_color);
+        let specular = (1.0 - dielectric * params.transmission) * (0.25 + 0.75 * params.metallic);
+        let clearcoat = 0.25 * params.clearcoat;
+        let transmission = dielectric * params.transmission;
+
+        let total = diffuse + specular + clearcoat + transmission;
+        if total <= 0.0 {
+            return None;
+        }
+
+        Some(LobeWeights {
+            diffuse: diffuse / total,
+            specular: specular / total,
+            clearcoat: clearcoat / total,
+            transmission: transmission / total
+        })
+    }
+
+    fn specular_distribution(params: &PrincipledParams) -> TrowbridgeReitz {
+        let alpha = TrowbridgeReitz::roughness_to_alpha(params.roughness);
+        TrowbridgeReitz::new(alpha, alpha)
+    }
+
+    fn clearcoat_alpha(params: &PrincipledParams) -> f64 {
+        (1.0 - params.clearcoat_gloss) * 0.1 + params.clearcoat_gloss * 0.001
+    }
+
+    fn eta(&self, rec: &HitRecord) -> f64 {
+        if rec.front_face { self.ior } else { 1.0 / self.ior }
+    }
+
+    pub fn sample(&self, ray: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        let frame = ONB::build_from_w(&rec.normal);
+        let wo = frame.to_local(&(-Vector3::normalize(&ray.direction)));
+        if wo.z <= 0.0 {
+            return None;
+        }
+
+        let params = self.params(rec);
+        let weights = Self::lobe_weights(&params)?;
+
+        let choice = random_double();
+        let wi = if choice < weights.diffuse {
+            Vector3::random_cosine_direction()
+        } else if choice < weights.diffuse + weights.specular {
+            reflect_local(&wo, &Self::specular_distribution(&params).sample_wm(&wo))
+        } else if choice < weights.diffuse + weights.specular + weights.clearcoat {
+            reflect_local(&wo, &Self::sample_gtr1(Self::clearcoat_alpha(&params)))
+        } else {
+            let distribution = Self::specular_distribution(&params);
+            let wm = distribution.sample_wm(&wo);
+            let eta = self.eta(rec);
+            if random_double() < fresnel_dielectric(Vector3::dot(&wo, &wm), eta) {
+                reflect_local(&wo, &wm)
+            } else {
+                refract_local(&wo, &wm, eta)?.0
+            }
+        };
+
+        let pdf = self.pdf_local(&params, &weights, rec, &wo, &wi);
+        if pdf <= 0.0 {
+            return None;
+        }
+
+        Some(ScatterRecord {
+            ray: Ray::with_wavelength(rec.point, frame.local(&wi), ray.time, ray.wavelength),
+            attenuation: self.eval_local(&params, rec, &wo, &wi),
+            pdf,
+            is_specular: false
+        })
+    }
+
+    pub fn eval(&self, ray: &Ray, rec: &HitRecord, direction: &Vector3) -> Color {
+        let frame = ONB::build_from_w(&rec.normal);
+        let wo = frame.to_local(&(-Vector3::normalize(&ray.direction)));
+        let wi = frame.to_local(&Vector3::normalize(direction));
+
+        self.eval_local(&self.params(rec), rec, &wo, &wi)
+    }
+
+    pub fn pdf(&self, ray: &Ray, rec: &HitRecord, direction: &Vector3) -> f64 {
+        let frame = ONB::build_from_w(&rec.normal);
+        let wo = frame.to_local(&(-Vector3::normalize(&ray.direction)));
+        let wi = frame.to_local(&Vector3::normalize(direction));
+
+        let params = self.params(rec);
+        match Self::lobe_weights(&params) {
+            Some(weights) => self.pdf_local(&params, &weights, rec, &wo, &wi),
+            None => 0.0
+        }
+    }
+
+    // Sum of all lobes, BSDF times |cos_i|
+    fn eval_local(&self, params: &PrincipledParams, rec: &HitRecord, wo: &Vector3, wi: &Vector3) -> Color {
+        let mut result = Color::new(0.0, 0.0, 0.0);
+        if wo.z <= 0.0 {
+            return result;
+        }
+
+        let dielectric = 1.0 - params.metallic;
+
+        if wi.z > 0.0 {
+            let wh = Vector3::normalize(&(*wi + *wo));
+            let cos_d = Vector3::dot(wi, &wh);
+
+            // Burley diffuse with retro-reflection, plus sheen
+            let fd90 = 0.5 + 2.0 * params.roughness * cos_d * cos_d;
+            let fl = |cosine: f64| 1.0 + (fd90 - 1.0) * schlick_weight(cosine);
+            let diffuse = params.base_color * (fl(wi.z) * fl(wo.z) / PI);
+            let sheen = params.sheen * schlick_weight(cos_d);
+            result += dielectric * (1.0 - params.transmission) * (diffuse + Color::new(sheen, sheen, sheen)) * wi.z;
+
+            // Metallic and dielectric GGX reflection with Schlick Fresnel
+            let f0 = (1.0 - params.metallic) * (0.08 * params.specular) * Color::new(1.0, 1.0, 1.0) + params.metallic * params.base_color;
+            let fresnel = f0 + (Color::new(1.0, 1.0, 1.0) - f0) * schlick_weight(cos_d);
+            let distribution = Self::specular_distribution(params);
+            let specular = distribution.d(&wh) * distribution.g(wo, wi) / (4.0 * wo.z);
+            result += (1.0 - dielectric * params.transmission) * specular * fresnel;
+
+            // Clearcoat with a GTR1 distribution and fixed roughness masking
+            if params.clearcoat > 0.0 {
+                let coat_fresnel = 0.04 + 0.96 * schlick_weight(cos_d);
+                let coat_distribution = TrowbridgeReitz::new(0.25, 0.25);
+                let coat = 0.25 * params.clearcoat * Self::gtr1(wh.z, Self::clearcoat_alpha(params)) * coat_distribution.g(wo, wi) * coat_fresnel / (4.0 * wo.z);
+                result += Color::new(coat, coat, coat);
+            }
+        }
+
+        if params.transmission > 0.0 && dielectric > 0.0 {
+            // Rough glass tinted by the base color
+            let glass = rough_dielectric_eval(self.eta(rec), &Self::specular_distribution(params), wo, wi);
+            result += dielectric * params.transmission * glass * params.base_color;
+        }
+
+        result
+    }
+
+    fn pdf_local(&self, params: &PrincipledParams, weights: &LobeWeights, rec: &HitRecord, wo: &Vector3, wi: &Vector3) -> f64 {
+        if wo.z <= 0.0 {
+            return 0.0;
+        }
+
+        let mut pdf = 0.0;
+
+        if wi.z > 0.0 {
+            let wh = Vector3::normalize(&(*wi + *wo));
+            let distribution = Self::specular_distribution(params);
+
+            pdf += weights.diffuse * wi.z / PI;
+            pdf += weights.specular * distribution.d_visible(wo, &wh) / (4.0 * Vector3::dot(wo, &wh).abs());
+            pdf += weights.clearcoat * Self::gtr1(wh.z, Self::clearcoat_alpha(params)) * wh.z / (4.0 * Vector3::dot(wo, &wh).abs());
+        }
+
+        if weights.transmission > 0.0 {
+            pdf += weights.transmission * rough_dielectric_pdf(self.eta(rec), &Self::specular_distribution(params), wo, wi);
+        }
+
+        pdf
+    }
+
+    // Generalized Trowbridge-Reitz with gamma = 1, as used for the clearcoat
+    fn gtr1(cos_theta_h: f64, alpha: f64) -> f64 {
+        let a2 = alpha * alpha;
+        (a2 - 1.0) / (PI * a2.ln() * (1.0 + (a2 - 1.0) * cos_theta_h * cos_theta_h))
+    }
+
+    // Samples a half vector proportional to gtr1 * cos_theta_h
+    fn sample_gtr1(alpha: f64) -> Vector3 {
+        let a2 = alpha * alpha;
+        let cos_theta = ((1.0 - a2.powf(1.0 - random_double())) / (1.0 - a2)).max(0.0).sqrt();
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * random_double();
+
+        Vector3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+    }
+}
+
+fn schlick_weight(cosine: f64) -> f64 {
+    (1.0 - clamp(cosine, 0.0, 1.0)).powi(5)
+}
@@ -1,9 +1,14 @@
 use crate::math::*;
 
+#[derive(Copy, Clone)]
 pub struct Ray {
     pub origin: Point3,
     pub direction: Vector3,
-    pub time: f64
+    pub time: f64,
+    pub wavelength: Option<f64>, // Set once the path has been narrowed down to a single wavelength, in nm
+    // Cone around the ray for texture filtering: its width at the origin, growing by spread per unit of distance
+    pub cone_width: f64,
+    pub cone_spread: f64
 }
 
 impl Ray {
@@ -11,10 +16,34 @@ impl Ray {
         Ray {
             origin,
             direction,
-            time
+            time,
+            wavelength: None,
+            cone_width: 0.0,
+            cone_spread: 0.0
         }
     }
 
+    pub fn with_wavelength(origin: Point3, direction: Vector3, time: f64, wavelength: Option<f64>) -> Ray {
+        Ray {
+            origin,
+            direction,
+            time,
+            wavelength,
+            cone_width: 0.0,
+            cone_spread: 0.0
+        }
+    }
+
+    pub fn with_cone(mut self, width: f64, spread: f64) -> Ray {
+        self.cone_width = width;
+        self.cone_spread = spread;
+        self
+    }
+
+    pub fn cone_width_at(&self, t: f64) -> f64 {
+        self.cone_width + self.cone_spread * t * self.direction.length()
+    }
+
 
     pub fn at(&self, t: f64) -> Point3 {
         self.origin + t * self.direction
@@ -0,0 +1,193 @@
+use crate::math::*;
+use crate::onb::*;
+use crate::environment::*;
+
+// Angular radius of the sun disk as seen from earth
+const SUN_ANGULAR_RADIUS: f64 = 0.267 * PI / 180.0;
+// Luminance of the sun before atmospheric attenuation, in cd/m^2
+const SUN_LUMINANCE: f64 = 2.0e9;
+// Converts cd/m^2 into renderer radiance units, so that a clear zenith lands around 1.0
+const LUMINANCE_SCALE: f64 = 1.0e-4;
+// Resolution of the tabulated sky used for importance sampling
+const SAMPLING_MAP_WIDTH: usize = 128;
+const SAMPLING_MAP_HEIGHT: usize = 64;
+
+// Preetham et al. "A Practical Analytic Model for Daylight" with an attenuated sun disk
+pub struct Sky {
+    pub sun_direction: Vector3,
+    pub intensity: f64,
+    pub sun_radiance: Color,
+    perez_y: [f64; 5],
+    perez_x: [f64; 5],
+    perez_yy: [f64; 5],
+    zenith: (f64, f64, f64), // Y (cd/m^2), x, y
+    theta_s: f64,
+    sampling_map: Option<EnvironmentMap>
+}
+
+impl Sky {
+    // elevation and azimuth in degrees, azimuth measured from +X towards +Z
+    pub fn new(elevation: f64, azimuth: f64, turbidity: f64, intensity: f64) -> Sky {
+        let elevation = degrees_to_radians(elevation);
+        let azimuth = degrees_to_radians(azimuth);
+        let sun_direction = Vector3::new(elevation.cos() * azimuth.cos(), elevation.sin(), elevation.cos() * azimuth.sin());
+
+        Self::with_sun_direction(&sun_direction, turbidity, intensity)
+    }
+
+    pub fn with_sun_direction(sun_direction: &Vector3, turbidity: f64, intensity: f64) -> Sky {
+        let sun_direction = Vector3::normalize(sun_direction);
+        let t = turbidity;
+        // The model is only defined for the sun above the horizon
+        let theta_s = f64::acos(clamp(sun_direction.y, 0.0, 1.0)).min(PI / 2.0 - 0.001);
+
+        let perez_y = [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703];
+        let perez_x = [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452];
+        let perez_yy = [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529];
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_luminance = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192) * 1000.0;
+
+        let theta_s2 = theta_s * theta_s;
+        let theta_s3 = theta_s2 * theta_s;
+        let zenith_x =
+            t * t * (0.00166 * theta_s3 - 0.00375 * theta_s2 + 0.00209 * theta_s) +
+            t * (-0.02903 * theta_s3 + 0.06377 * theta_s2 - 0.03202 * theta_s + 0.00394) +
+            (0.11693 * theta_s3 - 0.21196 * theta_s2 + 0.06052 * theta_s + 0.25886);
+        let zenith_y =
+            t * t * (0.00275 * theta_s3 - 0.00610 * theta_s2 + 0.00317 * theta_s) +
+            t * (-0.04214 * theta_s3 + 0.08970 * theta_s2 - 0.04153 * theta_s + 0.00516) +
+            (0.15346 * theta_s3 - 0.26756 * theta_s2 + 0.06670 * theta_s + 0.26688);
+
+        let mut sky = Sky {
+            sun_direction,
+            intensity,
+            sun_radiance: Self::sun_radiance(sun_direction.y, turbidity),
+            perez_y,
+            perez_x,
+            perez_yy,
+            zenith: (zenith_luminance, zenith_x, zenith_y),
+            theta_s,
+            sampling_map: None
+        };
+
+        // Tabulate the sky without the sun, which gets sampled separately
+        let mut data = Vec::with_capacity(SAMPLING_MAP_WIDTH * SAMPLING_MAP_HEIGHT * 3);
+        for j in 0..SAMPLING_MAP_HEIGHT {
+            let theta = PI * (j as f64 + 0.5) / SAMPLING_MAP_HEIGHT as f64;
+            for i in 0..SAMPLING_MAP_WIDTH {
+                let phi = 2.0 * PI * (i as f64 + 0.5) / SAMPLING_MAP_WIDTH as f64;
+                let direction = Vector3::new(-phi.cos() * theta.sin(), theta.cos(), phi.sin() * theta.sin());
+                let color = sky.sky_value(&direction);
+                data.push(color.x as f32);
+                data.push(color.y as f32);
+                data.push(color.z as f32);
+            }
+        }
+        sky.sampling_map = Some(EnvironmentMap::new(SAMPLING_MAP_WIDTH, SAMPLING_MAP_HEIGHT, data, 0.0, 1.0));
+
+        sky
+    }
+
+    // Sun color after travelling through the atmosphere, from Preetham's Rayleigh and aerosol terms
+    fn sun_radiance(cos_theta_s: f64, turbidity: f64) -> Color {
+        if cos_theta_s <= 0.0 {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+
+        let theta_degrees = f64::acos(cos_theta_s) * 180.0 / PI;
+        let optical_mass = 1.0 / (cos_theta_s + 0.15 * (93.885 - theta_degrees).powf(-1.253));
+        let beta = 0.04608 * turbidity - 0.04586;
+        let alpha = 1.3;
+
+        // Representative wavelengths (in micrometers) for the red, green and blue channels
+        let transmittance = |lambda: f64| {
+            let rayleigh = (-0.008735 * lambda.powf(-4.08) * optical_mass).exp();
+            let aerosol = (-beta * lambda.powf(-alpha) * optical_mass).exp();
+            rayleigh * aerosol
+        };
+
+        SUN_LUMINANCE * LUMINANCE_SCALE * Color::new(transmittance(0.68), transmittance(0.55), transmittance(0.44))
+    }
+
+    fn perez(coefficients: &[f64; 5], cos_theta: f64, gamma: f64) -> f64 {
+        let [a, b, c, d, e] = *coefficients;
+        (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos() * gamma.cos())
+    }
+
+    // Sky radiance without the sun disk. Directions below the horizon use the horizon value.
+    fn sky_value(&self, direction: &Vector3) -> Color {
+        let mut direction = Vector3::normalize(direction);
+        direction.y = direction.y.max(0.001);
+        let direction = Vector3::normalize(&direction);
+
+        let cos_theta = direction.y;
+        let gamma = f64::acos(clamp(Vector3::dot(&direction, &self.sun_direction), -1.0, 1.0));
+
+        let (zenith_luminance, zenith_x, zenith_y) = self.zenith;
+        let luminance = zenith_luminance * Self::perez(&self.perez_y, cos_theta, gamma) / Self::perez(&self.perez_y, 1.0, self.theta_s);
+        let x = zenith_x * Self::perez(&self.perez_x, cos_theta, gamma) / Self::perez(&self.perez_x, 1.0, self.theta_s);
+        let y = zenith_y * Self::perez(&self.perez_yy, cos_theta, gamma) / Self::perez(&self.perez_yy, 1.0, self.theta_s);
+
+        let xyz = Vector3::new(x / y * luminance, luminance, (1.0 - x - y) / y * luminance);
+        let rgb = xyz_to_rgb(&xyz);
+
+        // Clamp out of gamut colors
+        self.intensity * LUMINANCE_SCALE * Color::new(rgb.x.max(0.0), rgb.y.max(0.0), rgb.z.max(0.0))
+    }
+
+    fn in_sun_disk(&self, direction: &Vector3) -> bool {
+        Vector3::dot(&Vector3::normalize(direction), &self.sun_direction) >= SUN_ANGULAR_RADIUS.cos()
+    }
+
+    fn sun_probability(&self) -> f64 {
+        if self.sun_direction.y > 0.0 { 0.5 } else { 0.0 }
+    }
+
+    pub fn value(&self, direction: &Vector3) -> Color {
+        let mut color = self.sky_value(direction);
+
+        if self.sun_direction.y > 0.0 && self.in_sun_disk(direction) {
+            color += self.intensity * self.sun_radiance;
+        }
+
+        color
+    }
+
+    pub fn sample(&self) -> Option<(Vector3, Color, f64)> {
+        let direction = if random_double() < self.sun_probability() {
+            // Uniformly sample the cone subtended by the sun
+            let cos_max = SUN_ANGULAR_RADIUS.cos();
+            let z = 1.0 + random_double() * (cos_max - 1.0);
+            let phi = 2.0 * PI * random_double();
+            let r = (1.0 - z * z).max(0.0).sqrt();
+            ONB::build_from_w(&self.sun_direction).local(&Vector3::new(phi.cos() * r, phi.sin() * r, z))
+        } else if let Some(map) = &self.sampling_map {
+            map.sample()?.0
+        } else {
+            return None;
+        };
+
+        let pdf = self.pdf(&direction);
+        if pdf <= 0.0 {
+            return None;
+        }
+
+        Some((direction, self.value(&direction), pdf))
+    }
+
+    pub fn pdf(&self, direction: &Vector3) -> f64 {
+        let sun_probability = self.sun_probability();
+        let mut pdf = 0.0;
+
+        if sun_probability > 0.0 && self.in_sun_disk(direction) {
+            pdf += sun_probability / (2.0 * PI * (1.0 - SUN_ANGULAR_RADIUS.cos()));
+        }
+
+        if let Some(map) = &self.sampling_map {
+            pdf += (1.0 - sun_probability) * map.pdf(direction);
+        }
+
+        pdf
+    }
+}
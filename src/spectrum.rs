@@ -0,0 +1,300 @@
+use std::sync::OnceLock;
+use crate::math::*;
+
+// Visible range used when sampling wavelengths, in nanometers
+pub const LAMBDA_MIN: f64 = 380.0;
+pub const LAMBDA_MAX: f64 = 780.0;
+
+// Wavelength dependent index of refraction. Wavelengths are in nanometers, coefficients use micrometers.
+#[derive(Copy, Clone)]
+pub enum Dispersion {
+    Cauchy { a: f64, b: f64 },
+    Sellmeier { b: [f64; 3], c: [f64; 3] }
+}
+
+impl Dispersion {
+    pub fn bk7() -> Dispersion {
+        Dispersion::Sellmeier { b: [1.03961212, 0.231792344, 1.01046945], c: [0.00600069867, 0.0200179144, 103.560653] }
+    }
+
+    // Schott SF11 dense flint glass
+    pub fn dense_flint() -> Dispersion {
+        Dispersion::Sellmeier { b: [1.73759695, 0.313747346, 1.89878101], c: [0.013188707, 0.0623068142, 155.23629] }
+    }
+
+    pub fn diamond() -> Dispersion {
+        Dispersion::Sellmeier { b: [4.3356, 0.3306, 0.0], c: [0.01124, 0.030625, 0.0] }
+    }
+
+    pub fn ior(&self, wavelength: f64) -> f64 {
+        let lambda = wavelength / 1000.0;
+        let lambda2 = lambda * lambda;
+
+        match self {
+            Dispersion::Cauchy { a, b } => a + b / lambda2,
+            Dispersion::Sellmeier { b, c } => {
+                let mut n2 = 1.0;
+                for i in 0..3 {
+                    n2 += b[i] * lambda2 / (lambda2 - c[i]);
+                }
+                n2.sqrt()
+            }
+        }
+    }
+}
+
+pub fn sample_wavelength() -> f64 {
+    random_double_range(LAMBDA_MIN, LAMBDA_MAX)
+}
+
+// Piecewise gaussian used by the CIE 1931 fit from Wyman, Sloan and Shirley,
+// "Simple Analytic Approximations to the CIE XYZ Color Matching Functions"
+fn piecewise_gaussian(x: f64, mu: f64, sigma_low: f64, sigma_high: f64) -> f64 {
+    let t = (x - mu) / if x < mu { sigma_low } else { sigma_high };
+    (-0.5 * t * t).exp()
+}
+
+// CIE 1931 2 degree color matching functions at the given wavelength
+pub fn wavelength_to_xyz(wavelength: f64) -> Vector3 {
+    let x = 1.056 * piecewise_gaussian(wavelength, 599.8, 37.9, 31.0)
+          + 0.362 * piecewise_gaussian(wavelength, 442.0, 16.0, 26.7)
+          - 0.065 * piecewise_gaussian(wavelength, 501.1, 20.4, 26.2);
+    let y = 0.821 * piecewise_gaussian(wavelength, 568.8, 46.9, 40.5)
+          + 0.286 * piecewise_gaussian(wavelength, 530.9, 16.3, 31.1);
+    let z = 1.217 * piecewise_gaussian(wavelength, 437.0, 11.8, 36.0)
+          + 0.681 * piecewise_gaussian(wavelength, 459.0, 26.0, 13.8);
+
+    Vector3::new(x, y, z)
+}
+
+// Weight that turns a single uniformly sampled wavelength into an RGB color, normalized so that white light
+// averages to (1, 1, 1) over many samples
+pub fn wavelength_rgb_weight(wavelength: f64) -> Color {
+    static NORMALIZATION: OnceLock<Color> = OnceLock::new();
+
+    let normalization = NORMALIZATION.get_or_init(|| {
+        let steps = 4000;
+        let mut sum = Color::new(0.0, 0.0, 0.0);
+        for i in 0..steps {
+            let lambda = LAMBDA_MIN + (LAMBDA_MAX - LAMBDA_MIN) * (i as f64 + 0.5) / steps as f64;
+            sum += xyz_to_rgb(&wavelength_to_xyz(lambda));
+        }
+        sum / steps as f64
+    });
+
+    let rgb = xyz_to_rgb(&wavelength_to_xyz(wavelength));
+    Color::new(rgb.x / normalization.x, rgb.y / normalization.y, rgb.z / normalization.z)
+}
+
+// A spectral power distribution sampled at regular wavelength intervals, linearly interpolated in between
+#[derive(Clone)]
+pub enum Spectrum {
+    Tabulated { start: f64, step: f64, values: Vec<f64> },
+    // Planck's law for the given temperature in Kelvin, normalized to 1 at its peak wavelength
+    Blackbody { temperature: f64 }
+}
+
+// CIE standard illuminant D65, 380-780nm in 10nm steps
+const D65: [f64; 41] = [
+    49.98, 54.65, 82.75, 91.49, 93.43, 86.68, 104.86, 117.01, 117.81, 114.86,
+    115.92, 108.81, 109.35, 107.80, 104.79, 107.69, 104.41, 104.05, 100.00, 96.33,
+    95.79, 88.69, 90.01, 89.60, 87.70, 83.29, 83.70, 80.03, 80.21, 82.28,
+    78.28, 69.72, 71.61, 74.35, 61.60, 69.89, 75.09, 63.59, 46.42, 66.81,
+    63.38
+];
+
+// CIE standard illuminant F2 (cool white fluorescent), 380-780nm in 5nm steps
+const F2: [f64; 81] = [
+    1.18, 1.48, 1.84, 2.15, 3.44, 15.69, 3.85, 3.74, 4.19, 4.62,
+    5.06, 34.98, 11.81, 6.27, 6.63, 6.93, 7.19, 7.40, 7.54, 7.62,
+    7.65, 7.62, 7.62, 7.45, 7.28, 7.15, 7.05, 7.04, 7.16, 7.47,
+    8.04, 8.88, 10.01, 24.88, 16.64, 14.59, 16.16, 17.56, 18.62, 21.47,
+    22.79, 19.29, 18.66, 17.73, 16.54, 15.21, 13.80, 12.36, 10.95, 9.65,
+    8.40, 7.32, 6.31, 5.43, 4.68, 4.02, 3.45, 2.96, 2.55, 2.19,
+    1.89, 1.64, 1.53, 1.27, 1.10, 0.99, 0.88, 0.76, 0.68, 0.61,
+    0.56, 0.54, 0.51, 0.47, 0.47, 0.43, 0.46, 0.47, 0.40, 0.33,
+    0.27
+];
+
+impl Spectrum {
+    pub fn d65() -> Spectrum {
+        Spectrum::Tabulated { start: 380.0, step: 10.0, values: D65.to_vec() }
+    }
+
+    // CIE standard illuminant A (incandescent), defined analytically as a 2856K blackbody normalized at 560nm
+    pub fn illuminant_a() -> Spectrum {
+        let c2: f64 = 1.435e7;
+        let values = (0..81).map(|i| {
+            let lambda = 380.0 + 5.0 * i as f64;
+            100.0 * (560.0 / lambda).powi(5) * ((c2 / (2848.0 * 560.0)).exp() - 1.0) / ((c2 / (2848.0 * lambda)).exp() - 1.0)
+        }).collect();
+
+        Spectrum::Tabulated { start: 380.0, step: 5.0, values }
+    }
+
+    pub fn blackbody(temperature: f64) -> Spectrum {
+        Spectrum::Blackbody { temperature }
+    }
+
+    pub fn f2() -> Spectrum {
+        Spectrum::Tabulated { start: 380.0, step: 5.0, values: F2.to_vec() }
+    }
+
+    pub fn sample(&self, wavelength: f64) -> f64 {
+        match self {
+            Spectrum::Tabulated { start, step, values } => sample_table(*start, *step, values, wavelength),
+            Spectrum::Blackbody { temperature } => {
+                // Wien's displacement law gives the peak wavelength in nm
+                let peak = 2.897_771_955e6 / temperature;
+                planck(wavelength, *temperature) / planck(peak, *temperature)
+            }
+        }
+    }
+
+    // Integrates the spectrum against the color matching functions, normalized so a flat spectrum of 1 has Y = 1
+    pub fn to_xyz(&self) -> Vector3 {
+        let mut xyz = Vector3::new(0.0, 0.0, 0.0);
+        let mut lambda = LAMBDA_MIN;
+        while lambda <= LAMBDA_MAX {
+            xyz += self.sample(lambda) * wavelength_to_xyz(lambda);
+            lambda += 1.0;
+        }
+
+        xyz / cie_y_integral()
+    }
+}
+
+// Absolute blackbody radiance integrated like Spectrum::to_xyz, so unlike Spectrum::blackbody hotter bodies are brighter
+pub fn blackbody_xyz(temperature: f64) -> Vector3 {
+    let mut xyz = Vector3::new(0.0, 0.0, 0.0);
+    let mut lambda = LAMBDA_MIN;
+    while lambda <= LAMBDA_MAX {
+        xyz += planck(lambda, temperature) * wavelength_to_xyz(lambda);
+        lambda += 1.0;
+    }
+
+    xyz / cie_y_integral()
+}
+
+// Spectral radiance of a blackbody in W/(sr m^2 m) at a wavelength given in nm
+pub fn planck(wavelength: f64, temperature: f64) -> f64 {
+    let c = 299_792_458.0;
+    let h = 6.626_070_15e-34;
+    let kb = 1.380_649e-23;
+
+    let l = wavelength * 1e-9;
+    (2.0 * h * c * c) / (l.powi(5) * ((h * c / (l * kb * temperature)).exp() - 1.0))
+}
+
+fn sample_table(start: f64, step: f64, values: &[f64], wavelength: f64) -> f64 {
+    let x = (wavelength - start) / step;
+    if x <= 0.0 {
+        return values[0];
+    }
+
+    let i = x.floor() as usize;
+    if i + 1 >= values.len() {
+        return values[values.len() - 1];
+    }
+
+    let t = x - i as f64;
+    (1.0 - t) * values[i] + t * values[i + 1]
+}
+
+// Integral of the y color matching function over the sampled range, with 1nm steps
+pub fn cie_y_integral() -> f64 {
+    static INTEGRAL: OnceLock<f64> = OnceLock::new();
+
+    *INTEGRAL.get_or_init(|| {
+        let mut sum = 0.0;
+        let mut lambda = LAMBDA_MIN;
+        while lambda <= LAMBDA_MAX {
+            sum += wavelength_to_xyz(lambda).y;
+            lambda += 1.0;
+        }
+        sum
+    })
+}
+
+// Hero wavelength sampling: one uniformly sampled wavelength plus two more evenly rotated through the
+// visible range. The three wavelengths travel in the x, y and z lanes of a Color.
+pub fn sample_wavelengths() -> Vector3 {
+    let hero = sample_wavelength();
+    let range = LAMBDA_MAX - LAMBDA_MIN;
+    let rotate = |i: f64| LAMBDA_MIN + (hero - LAMBDA_MIN + i * range / 3.0) % range;
+
+    Vector3::new(hero, rotate(1.0), rotate(2.0))
+}
+
+// Converts the radiance carried for three sampled wavelengths into linear sRGB
+pub fn spectral_to_rgb(radiance: &Color, wavelengths: &Vector3) -> Color {
+    let xyz = radiance.x * wavelength_to_xyz(wavelengths.x)
+            + radiance.y * wavelength_to_xyz(wavelengths.y)
+            + radiance.z * wavelength_to_xyz(wavelengths.z);
+
+    // Each wavelength has pdf 1 / (LAMBDA_MAX - LAMBDA_MIN), and we average over the three of them
+    xyz_to_rgb(&(xyz * ((LAMBDA_MAX - LAMBDA_MIN) / (3.0 * cie_y_integral()))))
+}
+
+// Smits, "An RGB-to-Spectrum Conversion for Reflectances". Ten bins spanning 380-720nm.
+const SMITS_WHITE: [f64; 10] = [1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000];
+const SMITS_CYAN: [f64; 10] = [0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000];
+const SMITS_MAGENTA: [f64; 10] = [1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959];
+const SMITS_YELLOW: [f64; 10] = [0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840];
+const SMITS_RED: [f64; 10] = [0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149];
+const SMITS_GREEN: [f64; 10] = [0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0000];
+const SMITS_BLUE: [f64; 10] = [1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496];
+
+fn rgb_to_spectrum(rgb: &Color, wavelength: f64) -> f64 {
+    let bin = ((wavelength - 380.0) / 34.0).floor().clamp(0.0, 9.0) as usize;
+    let (r, g, b) = (rgb.x, rgb.y, rgb.z);
+
+    if r <= g && r <= b {
+        let mut value = r * SMITS_WHITE[bin];
+        if g <= b {
+            value += (g - r) * SMITS_CYAN[bin] + (b - g) * SMITS_BLUE[bin];
+        } else {
+            value += (b - r) * SMITS_CYAN[bin] + (g - b) * SMITS_GREEN[bin];
+        }
+        value
+    } else if g <= r && g <= b {
+        let mut value = g * SMITS_WHITE[bin];
+        if r <= b {
+            value += (r - g) * SMITS_MAGENTA[bin] + (b - r) * SMITS_BLUE[bin];
+        } else {
+            value += (b - g) * SMITS_MAGENTA[bin] + (r - b) * SMITS_RED[bin];
+        }
+        value
+    } else {
+        let mut value = b * SMITS_WHITE[bin];
+        if r <= g {
+            value += (r - b) * SMITS_YELLOW[bin] + (g - r) * SMITS_GREEN[bin];
+        } else {
+            value += (g - b) * SMITS_YELLOW[bin] + (r - g) * SMITS_RED[bin];
+        }
+        value
+    }
+}
+
+// Upsamples a reflectance-like RGB value (albedos, attenuations) at the three path wavelengths
+pub fn rgb_to_reflectance_samples(rgb: &Color, wavelengths: &Vector3) -> Color {
+    Color::new(
+        rgb_to_spectrum(rgb, wavelengths.x),
+        rgb_to_spectrum(rgb, wavelengths.y),
+        rgb_to_spectrum(rgb, wavelengths.z)
+    )
+}
+
+// Upsamples an RGB emission. Emitters are modulated by D65 so that RGB white light stays white on the film.
+pub fn rgb_to_illuminant_samples(rgb: &Color, wavelengths: &Vector3) -> Color {
+    static D65_LUMINANCE: OnceLock<f64> = OnceLock::new();
+    let luminance = *D65_LUMINANCE.get_or_init(|| Spectrum::d65().to_xyz().y);
+    let d65 = |wavelength: f64| sample_table(380.0, 10.0, &D65, wavelength);
+
+    let reflectance = rgb_to_reflectance_samples(rgb, wavelengths);
+    Color::new(
+        reflectance.x * d65(wavelengths.x),
+        reflectance.y * d65(wavelengths.y),
+        reflectance.z * d65(wavelengths.z)
+    ) / luminance
+}
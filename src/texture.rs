@@ -1,11 +1,55 @@
 use crate::math::*;
 use crate::perlin::Perlin;
+use crate::hittable::HitRecord;
 
 pub enum Texture {
     SolidColor(Color),
+    Scalar(f64), // Constant for scalar parameters such as roughness, read as a gray color
     Checker(Color, Color),
     Noise(Perlin, f64),
-    Image(usize, usize, usize, Vec<u8>) // width, height, bytes per scanline, data
+    Image(usize, usize, usize, usize, Vec<u8>, Filter, (Wrap, Wrap), Vec<MipLevel>) // width, height, bytes per pixel, bytes per scanline, data, filter, wrap in u and v, coarser mip levels
+}
+
+// Image halved in size from the previous level, with the same bytes per pixel and tightly packed scanlines
+pub struct MipLevel {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>
+}
+
+// Reconstruction of image textures between texel centers
+#[derive(Copy, Clone)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+    Bicubic, // Catmull-Rom spline over the 4x4 nearest texels
+    // Filters over the ray's footprint from the mip map, and are bilinear for rays without one
+    Trilinear, // Bilinear lookups in the two levels closest to the footprint's larger axis
+    Ewa // Elliptically weighted average over the footprint's ellipse, for surfaces seen at grazing angles
+}
+
+// What image textures repeat outside of [0,1] texture coordinates
+#[derive(Copy, Clone)]
+pub enum Wrap {
+    Repeat,
+    Mirror, // Repeats flipped every other time, so edges meet their own texels
+    Clamp
+}
+
+impl Wrap {
+    fn texel(&self, i: i64, size: usize) -> usize {
+        let size = size as i64;
+        let i = match self {
+            Wrap::Repeat => i.rem_euclid(size),
+            Wrap::Mirror => {
+                let m = i.rem_euclid(2 * size);
+                if m < size { m } else { 2 * size - 1 - m }
+            },
+            Wrap::Clamp => i.clamp(0, size - 1)
+        };
+
+        i as usize
+    }
 }
 
 impl Texture {
@@ -18,12 +62,274 @@ impl Texture {
             stb_image::image::LoadResult::ImageF32(_) => { panic!("Wrong image format!") }
         };
 
-        Texture::Image(img.width as usize, img.height as usize, 3 * img.width as usize, img.data)
+        // 1 (gray), 2 (gray, alpha), 3 (RGB) or 4 (RGBA) channels
+        let (w, h, bytes_per_pixel) = (img.width as usize, img.height as usize, img.depth);
+        let levels = build_mip_levels(w, h, bytes_per_pixel, &img.data);
+        Texture::Image(w, h, bytes_per_pixel, bytes_per_pixel * w, img.data, Filter::Nearest, (Wrap::Clamp, Wrap::Clamp), levels)
     }
+
+    // Image textures are loaded with nearest filtering and clamped coordinates, other textures are left as they are
+    pub fn with_filter(self, filter: Filter) -> Texture {
+        match self {
+            Texture::Image(w, h, bytes_per_pixel, bytes_per_scanline, data, _, wrap, levels) => Texture::Image(w, h, bytes_per_pixel, bytes_per_scanline, data, filter, wrap, levels),
+            texture => texture
+        }
+    }
+
+    pub fn with_wrap(self, wrap_u: Wrap, wrap_v: Wrap) -> Texture {
+        let wrap = (wrap_u, wrap_v);
+        match self {
+            Texture::Image(w, h, bytes_per_pixel, bytes_per_scanline, data, filter, _, levels) => Texture::Image(w, h, bytes_per_pixel, bytes_per_scanline, data, filter, wrap, levels),
+            texture => texture
+        }
+    }
+
+    // Opacity read from the alpha channel of images that have one, and from the scalar value otherwise
+    pub fn get_opacity_value(&self, u: f64, v: f64, p: &Point3) -> f64 {
+        match self {
+            Texture::Image(w, h, bytes_per_pixel, bytes_per_scanline, data, filter, wrap, _) if *bytes_per_pixel % 2 == 0 => {
+                let alpha = image_lookup(*w, *h, *filter, *wrap, u, v, |i, j| {
+                    let alpha = data[j * bytes_per_scanline + i * bytes_per_pixel + bytes_per_pixel - 1] as f64 / 255.0;
+                    Color::new(alpha, alpha, alpha)
+                });
+                clamp(alpha.x, 0.0, 1.0)
+            },
+            _ => {
+                self.get_scalar_value(u, v, p)
+            }
+        }
+    }
+}
+
+// Box filtered pyramid down to a single texel. Odd sizes drop their last row or column
+fn build_mip_levels(w: usize, h: usize, bytes_per_pixel: usize, data: &[u8]) -> Vec<MipLevel> {
+    let mut levels: Vec<MipLevel> = Vec::new();
+    let (mut width, mut height) = (w, h);
+
+    while width > 1 || height > 1 {
+        let source = levels.last().map_or(data, |level| &level.data);
+        let (level_width, level_height) = ((width / 2).max(1), (height / 2).max(1));
+
+        let mut level_data = Vec::with_capacity(level_width * level_height * bytes_per_pixel);
+        for j in 0..level_height {
+            for i in 0..level_width {
+                for c in 0..bytes_per_pixel {
+                    let mut sum = 0;
+                    for (di, dj) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                        let x = (2 * i + di).min(width - 1);
+                        let y = (2 * j + dj).min(height - 1);
+                        sum += source[(y * width + x) * bytes_per_pixel + c] as u32;
+                    }
+                    level_data.push(((sum + 2) / 4) as u8);
+                }
+            }
+        }
+
+        levels.push(MipLevel { width: level_width, height: level_height, data: level_data });
+        width = level_width;
+        height = level_height;
+    }
+
+    levels
+}
+
+fn texel_color(data: &[u8], offset: usize, bytes_per_pixel: usize) -> Color {
+    let color_scale = 1.0 / 255.0;
+    if bytes_per_pixel >= 3 {
+        Color::new(color_scale * data[offset] as f64, color_scale * data[offset + 1] as f64, color_scale * data[offset + 2] as f64)
+    } else {
+        Color::new(1.0, 1.0, 1.0) * (color_scale * data[offset] as f64)
+    }
+}
+
+// Image texture with its mip levels, as filtered over ray footprints
+struct MipChain<'a> {
+    width: usize,
+    height: usize,
+    bytes_per_pixel: usize,
+    data: &'a [u8],
+    levels: &'a [MipLevel],
+    filter: Filter,
+    wrap: (Wrap, Wrap)
+}
+
+// Area of texture space seen by a ray: the texture coordinates at its center, and the changes in (u, v) along the
+// ellipse's two axes
+#[derive(Copy, Clone)]
+struct Footprint {
+    u: f64,
+    v: f64,
+    axis_0: (f64, f64),
+    axis_1: (f64, f64)
+}
+
+// Color of an image texture filtered over a footprint
+fn mip_lookup(image: &MipChain, footprint: &Footprint) -> Color {
+    let (w, h, bytes_per_pixel, wrap) = (image.width, image.height, image.bytes_per_pixel, image.wrap);
+    let (u, v) = (footprint.u, footprint.v);
+    let level = |l: usize| -> (usize, usize, &[u8]) {
+        if l == 0 { (w, h, image.data) } else { (image.levels[l - 1].width, image.levels[l - 1].height, &image.levels[l - 1].data) }
+    };
+    let bilinear = |l: usize| -> Color {
+        let (lw, lh, ldata) = level(l);
+        image_lookup(lw, lh, Filter::Bilinear, wrap, u, v, |i, j| texel_color(ldata, (j * lw + i) * bytes_per_pixel, bytes_per_pixel))
+    };
+
+    // Footprint axes in texels of the full resolution image
+    let (mut major, mut minor) = (footprint.axis_0, footprint.axis_1);
+    let length = |axis: (f64, f64)| ((axis.0 * w as f64).powi(2) + (axis.1 * h as f64).powi(2)).sqrt();
+    if length(major) < length(minor) {
+        std::mem::swap(&mut major, &mut minor);
+    }
+
+    let lookup_width = match image.filter {
+        Filter::Ewa => {
+            // Very eccentric footprints would cover too many texels, so they are blurred along their minor axis
+            const MAX_ANISOTROPY: f64 = 8.0;
+            let (major_length, minor_length) = (length(major), length(minor));
+            if minor_length * MAX_ANISOTROPY < major_length && minor_length > 0.0 {
+                let scale = major_length / (minor_length * MAX_ANISOTROPY);
+                minor = (minor.0 * scale, minor.1 * scale);
+            }
+            length(minor)
+        },
+        _ => length(major)
+    };
+
+    let lod = lookup_width.max(1e-8).log2();
+    if lod <= 0.0 {
+        return bilinear(0);
+    }
+
+    let last = image.levels.len();
+    if lod >= last as f64 {
+        return bilinear(last);
+    }
+
+    let l = lod.floor() as usize;
+    let t = lod - l as f64;
+    match image.filter {
+        Filter::Ewa => {
+            let ellipse = Footprint { u, v, axis_0: major, axis_1: minor };
+            let ewa = |l: usize| -> Color {
+                let (lw, lh, ldata) = level(l);
+                ewa_lookup(lw, lh, wrap, &ellipse, |i, j| texel_color(ldata, (j * lw + i) * bytes_per_pixel, bytes_per_pixel))
+            };
+            (1.0 - t) * ewa(l) + t * ewa(l + 1)
+        },
+        _ => (1.0 - t) * bilinear(l) + t * bilinear(l + 1)
+    }
+}
+
+// Gaussian weighted average of the texels inside the footprint's ellipse, following Heckbert's EWA filter as used
+// in pbrt
+fn ewa_lookup<F: Fn(usize, usize) -> Color>(w: usize, h: usize, wrap: (Wrap, Wrap), footprint: &Footprint, texel: F) -> Color {
+    // Texel space with V flipped to image coordinates, and texel centers at integers
+    let s = footprint.u * w as f64 - 0.5;
+    let t = (1.0 - footprint.v) * h as f64 - 0.5;
+    let (ds_0, dt_0) = (footprint.axis_0.0 * w as f64, -footprint.axis_0.1 * h as f64);
+    let (ds_1, dt_1) = (footprint.axis_1.0 * w as f64, -footprint.axis_1.1 * h as f64);
+
+    // Implicit ellipse a s^2 + b s t + c t^2 < 1, widened by a texel so it always covers at least one
+    let mut a = dt_0 * dt_0 + dt_1 * dt_1 + 1.0;
+    let mut b = -2.0 * (ds_0 * dt_0 + ds_1 * dt_1);
+    let mut c = ds_0 * ds_0 + ds_1 * ds_1 + 1.0;
+    let inv_f = 1.0 / (a * c - b * b * 0.25);
+    a *= inv_f;
+    b *= inv_f;
+    c *= inv_f;
+
+    let det = -b * b + 4.0 * a * c;
+    let inv_det = 1.0 / det;
+    let s_extent = 2.0 * inv_det * (det * c).sqrt();
+    let t_extent = 2.0 * inv_det * (a * det).sqrt();
+
+    const ALPHA: f64 = 2.0;
+    let mut sum = Color::new(0.0, 0.0, 0.0);
+    let mut weight_sum = 0.0;
+    for j in (t - t_extent).ceil() as i64..=(t + t_extent).floor() as i64 {
+        let tt = j as f64 - t;
+        for i in (s - s_extent).ceil() as i64..=(s + s_extent).floor() as i64 {
+            let ss = i as f64 - s;
+            let r2 = a * ss * ss + b * ss * tt + c * tt * tt;
+            if r2 < 1.0 {
+                let weight = (-ALPHA * r2).exp() - (-ALPHA).exp();
+                sum += weight * texel(wrap.0.texel(i, w), wrap.1.texel(j, h));
+                weight_sum += weight;
+            }
+        }
+    }
+
+    if weight_sum > 0.0 { sum / weight_sum } else { texel(wrap.0.texel(s.round() as i64, w), wrap.1.texel(t.round() as i64, h)) }
+}
+
+// Filtered image value at texture coordinates, given the value of each texel
+fn image_lookup<F: Fn(usize, usize) -> Color>(w: usize, h: usize, filter: Filter, wrap: (Wrap, Wrap), u: f64, v: f64, texel: F) -> Color {
+    // Texel space with V flipped to image coordinates
+    let x = u * w as f64;
+    let y = (1.0 - v) * h as f64;
+    let fetch = |i: i64, j: i64| texel(wrap.0.texel(i, w), wrap.1.texel(j, h));
+
+    match filter {
+        Filter::Nearest => {
+            fetch(x.floor() as i64, y.floor() as i64)
+        },
+        Filter::Bilinear | Filter::Trilinear | Filter::Ewa => {
+            // Texel centers sit at half integer coordinates
+            let (x, y) = (x - 0.5, y - 0.5);
+            let (i, j) = (x.floor() as i64, y.floor() as i64);
+            let (tx, ty) = (x - x.floor(), y - y.floor());
+
+            (1.0 - ty) * ((1.0 - tx) * fetch(i, j) + tx * fetch(i + 1, j))
+                + ty * ((1.0 - tx) * fetch(i, j + 1) + tx * fetch(i + 1, j + 1))
+        },
+        Filter::Bicubic => {
+            let (x, y) = (x - 0.5, y - 0.5);
+            let (i, j) = (x.floor() as i64, y.floor() as i64);
+            let wx = catmull_rom_weights(x - x.floor());
+            let wy = catmull_rom_weights(y - y.floor());
+
+            let mut color = Color::new(0.0, 0.0, 0.0);
+            for (dj, wy) in wy.iter().enumerate() {
+                for (di, wx) in wx.iter().enumerate() {
+                    color += wx * wy * fetch(i + di as i64 - 1, j + dj as i64 - 1);
+                }
+            }
+
+            // The spline overshoots around sharp edges
+            Color::new(color.x.max(0.0), color.y.max(0.0), color.z.max(0.0))
+        }
+    }
+}
+
+// Weights of the texels at offsets -1, 0, 1 and 2 from the one before the lookup point, t being the distance past it
+fn catmull_rom_weights(t: f64) -> [f64; 4] {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    [
+        0.5 * (-t3 + 2.0 * t2 - t),
+        0.5 * (3.0 * t3 - 5.0 * t2 + 2.0),
+        0.5 * (-3.0 * t3 + 4.0 * t2 + t),
+        0.5 * (t3 - t2)
+    ]
 }
 
 pub trait ColorValue {
     fn get_color_value(&self, u: f64, v: f64, p: &Point3) -> Color;
+
+    // Textures driving scalar parameters (roughness, metallic, ...) are read through their luminance
+    fn get_scalar_value(&self, u: f64, v: f64, p: &Point3) -> f64 {
+        luminance(&self.get_color_value(u, v, p))
+    }
+
+    // Value at a hit, filtered over the texture footprint of the ray that found it where the texture supports it
+    fn get_hit_color_value(&self, rec: &HitRecord) -> Color {
+        self.get_color_value(rec.u, rec.v, &rec.point)
+    }
+
+    fn get_hit_scalar_value(&self, rec: &HitRecord) -> f64 {
+        luminance(&self.get_hit_color_value(rec))
+    }
 }
 
 impl ColorValue for Texture {
@@ -32,6 +338,9 @@ impl ColorValue for Texture {
             Texture::SolidColor(color) => {
                 *color
             },
+            Texture::Scalar(value) => {
+                Color::new(*value, *value, *value)
+            },
             Texture::Checker(even, odd) => {
                 let sines = (10.0 * p.x).sin() * (10.0 * p.y).sin() * (10.0 * p.z).sin();
                 if sines < 0.0 {
@@ -43,33 +352,20 @@ impl ColorValue for Texture {
             Texture::Noise(perlin, scale) => {
                 Color::new(1.0, 1.0, 1.0) * 0.5 * (1.0 + (scale * p.z + 10.0 * perlin.turb(p, 7)).sin())
             },
-            Texture::Image(w, h, bytes_per_scanline, data) => {
-                // Clamp input texture coordinates to [0,1] x [1,0]
-                let u = clamp(u, 0.0, 1.0);
-                let v = 1.0 - clamp(v, 0.0, 1.0); // Flip V to image coordinates
-
-                //eprintln!("U {} V {}", u, v);
-                
-                let mut i = (u * *w as f64) as usize;
-                let mut j = (v * *h as f64) as usize;
-
-                // Clamp integer mapping, since actual coordinates should be less than 1.0
-                if i >= *w {
-                    i = w - 1;
-                }
-
-                if j >= *h {
-                    j = h - 1;
-                }
-
-                let color_scale = 1.0 / 255.0;
-                let pixel: [f64; 3] = unsafe {
-                    let ptr: *const u8 = data.as_ptr().offset((j * bytes_per_scanline + i * 3) as isize);
-
-                    [color_scale * *ptr as f64, color_scale * *ptr.offset(1) as f64, color_scale * *ptr.offset(2) as f64]
-                };
+            Texture::Image(w, h, bytes_per_pixel, bytes_per_scanline, data, filter, wrap, _) => {
+                image_lookup(*w, *h, *filter, *wrap, u, v, |i, j| texel_color(data, j * bytes_per_scanline + i * bytes_per_pixel, *bytes_per_pixel))
+            }
+        }
+    }
 
-                Color::new(pixel[0], pixel[1], pixel[2])
+    fn get_hit_color_value(&self, rec: &HitRecord) -> Color {
+        match self {
+            Texture::Image(w, h, bytes_per_pixel, _, data, filter @ (Filter::Trilinear | Filter::Ewa), wrap, levels) => {
+                let image = MipChain { width: *w, height: *h, bytes_per_pixel: *bytes_per_pixel, data, levels, filter: *filter, wrap: *wrap };
+                mip_lookup(&image, &Footprint { u: rec.u, v: rec.v, axis_0: (rec.dudx, rec.dvdx), axis_1: (rec.dudy, rec.dvdy) })
+            },
+            _ => {
+                self.get_color_value(rec.u, rec.v, &rec.point)
             }
         }
     }